@@ -0,0 +1,408 @@
+use rgx::core::{Rect, Rgba8};
+use rgx::math::Point2;
+
+/// A rectangular buffer of pixels, with rows ordered from top to bottom. This is
+/// the order in which pixels are stored in view snapshots and uploaded to textures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitmap {
+    /// Width in pixels.
+    pub w: u32,
+    /// Height in pixels.
+    pub h: u32,
+    /// Pixel data.
+    pub pixels: Vec<Rgba8>,
+}
+
+impl Bitmap {
+    /// How many times bitmaps are upscaled before being rotated by `rotsprite`.
+    pub const ROTSPRITE_UPSCALE: u32 = 8;
+
+    /// Create a bitmap from pixels.
+    pub fn new(w: u32, h: u32, pixels: Vec<Rgba8>) -> Self {
+        debug_assert_eq!(pixels.len(), Self::len(w, h));
+
+        Self { w, h, pixels }
+    }
+
+    /// Create a bitmap filled with a color.
+    pub fn filled(w: u32, h: u32, color: Rgba8) -> Self {
+        Self::new(w, h, vec![color; Self::len(w, h)])
+    }
+
+    /// Create a bitmap from a function of the pixel coordinates.
+    pub fn from_fn<F>(w: u32, h: u32, f: F) -> Self
+    where
+        F: Fn(u32, u32) -> Rgba8,
+    {
+        let mut pixels = Vec::with_capacity(Self::len(w, h));
+
+        for y in 0..h {
+            for x in 0..w {
+                pixels.push(f(x, y));
+            }
+        }
+        Self::new(w, h, pixels)
+    }
+
+    /// Get the pixel at the given coordinates, with `(0, 0)` being the top-left corner.
+    pub fn get(&self, x: u32, y: u32) -> Rgba8 {
+        self.pixels[self.index(x, y)]
+    }
+
+    /// Set the pixel at the given coordinates, with `(0, 0)` being the top-left corner.
+    pub fn set(&mut self, x: u32, y: u32, color: Rgba8) {
+        let i = self.index(x, y);
+        self.pixels[i] = color;
+    }
+
+    /// Number of pixels in a bitmap of the given size.
+    fn len(w: u32, h: u32) -> usize {
+        (w as usize)
+            .checked_mul(h as usize)
+            .expect("bitmap size is not unreasonably large")
+    }
+
+    /// Index of the pixel at the given coordinates.
+    fn index(&self, x: u32, y: u32) -> usize {
+        y as usize * self.w as usize + x as usize
+    }
+
+    /// Convert view coordinates to bitmap coordinates, given the view area the
+    /// bitmap covers. Returns `None` if the point is outside of the area.
+    pub fn coords(area: Rect<i32>, p: Point2<i32>) -> Option<(u32, u32)> {
+        if area.contains(p) {
+            Some(((p.x - area.x1) as u32, (area.y2 - p.y - 1) as u32))
+        } else {
+            None
+        }
+    }
+
+    /// Copy another bitmap into this one, with its top-left corner at the given
+    /// coordinates. Pixels falling outside of this bitmap are ignored.
+    pub fn blit(&mut self, other: &Bitmap, x: u32, y: u32) {
+        for oy in 0..other.h.min(self.h.saturating_sub(y)) {
+            for ox in 0..other.w.min(self.w.saturating_sub(x)) {
+                self.set(x + ox, y + oy, other.get(ox, oy));
+            }
+        }
+    }
+
+    /// Get the pixel at the given coordinates, clamping them to the bitmap edges.
+    fn get_clamped(&self, x: i32, y: i32) -> Rgba8 {
+        self.get(
+            x.clamp(0, self.w as i32 - 1) as u32,
+            y.clamp(0, self.h as i32 - 1) as u32,
+        )
+    }
+
+    /// Scale the bitmap by the given factors. Uses nearest-neighbour sampling, or,
+    /// if `epx` is set and the bitmap is scaled uniformly by two or three, the
+    /// Scale2x and Scale3x (EPX) algorithms, which preserve diagonal edges.
+    pub fn scale(&self, sx: f32, sy: f32, epx: bool) -> Self {
+        if epx && sx == 2. && sy == 2. {
+            return self.scale2x();
+        }
+        if epx && sx == 3. && sy == 3. {
+            return self.scale3x();
+        }
+        let (w, h) = Self::scaled_size(self.w, self.h, sx, sy);
+
+        self.resize(w, h)
+    }
+
+    /// Size of a bitmap of the given size once scaled by the given factors. This
+    /// is the size of the result of [`Bitmap::scale`], and can be checked before
+    /// scaling. It is never smaller than one pixel.
+    pub fn scaled_size(w: u32, h: u32, sx: f32, sy: f32) -> (u32, u32) {
+        (
+            ((w as f32 * sx).round() as u32).max(1),
+            ((h as f32 * sy).round() as u32).max(1),
+        )
+    }
+
+    /// Resize the bitmap to the given size, using nearest-neighbour sampling.
+    pub fn resize(&self, w: u32, h: u32) -> Self {
+        Self::from_fn(w, h, |x, y| {
+            let sx = ((x as f32 + 0.5) * self.w as f32 / w as f32) as u32;
+            let sy = ((y as f32 + 0.5) * self.h as f32 / h as f32) as u32;
+
+            self.get(sx.min(self.w - 1), sy.min(self.h - 1))
+        })
+    }
+
+    /// Scale the bitmap by two, using the Scale2x algorithm.
+    pub fn scale2x(&self) -> Self {
+        Self::from_fn(self.w * 2, self.h * 2, |x, y| {
+            let (px, py) = ((x / 2) as i32, (y / 2) as i32);
+            let p = self.get_clamped(px, py);
+            let a = self.get_clamped(px, py - 1);
+            let b = self.get_clamped(px + 1, py);
+            let c = self.get_clamped(px - 1, py);
+            let d = self.get_clamped(px, py + 1);
+
+            match (x % 2, y % 2) {
+                (0, 0) if c == a && c != d && a != b => a,
+                (1, 0) if a == b && a != c && b != d => b,
+                (0, 1) if d == c && d != b && c != a => c,
+                (1, 1) if b == d && b != a && d != c => d,
+                _ => p,
+            }
+        })
+    }
+
+    /// Scale the bitmap by three, using the Scale3x algorithm.
+    pub fn scale3x(&self) -> Self {
+        Self::from_fn(self.w * 3, self.h * 3, |x, y| {
+            let (px, py) = ((x / 3) as i32, (y / 3) as i32);
+            let n = |dx, dy| self.get_clamped(px + dx, py + dy);
+            let (a, b, c) = (n(-1, -1), n(0, -1), n(1, -1));
+            let (d, e, f) = (n(-1, 0), n(0, 0), n(1, 0));
+            let (g, h, i) = (n(-1, 1), n(0, 1), n(1, 1));
+
+            if b == h || d == f {
+                return e;
+            }
+            match (x % 3, y % 3) {
+                (0, 0) if d == b => d,
+                (1, 0) if (d == b && e != c) || (b == f && e != a) => b,
+                (2, 0) if b == f => f,
+                (0, 1) if (d == b && e != g) || (d == h && e != a) => d,
+                (2, 1) if (b == f && e != i) || (h == f && e != c) => f,
+                (0, 2) if d == h => d,
+                (1, 2) if (d == h && e != i) || (h == f && e != g) => h,
+                (2, 2) if h == f => f,
+                _ => e,
+            }
+        })
+    }
+
+    /// Mirror the bitmap horizontally.
+    pub fn flip_x(&self) -> Self {
+        Self::from_fn(self.w, self.h, |x, y| self.get(self.w - x - 1, y))
+    }
+
+    /// Mirror the bitmap vertically.
+    pub fn flip_y(&self) -> Self {
+        Self::from_fn(self.w, self.h, |x, y| self.get(x, self.h - y - 1))
+    }
+
+    /// Outline the opaque pixels with the given color. Transparent pixels adjacent to
+    /// opaque ones are painted, or, if `inside` is set, opaque pixels adjacent to
+    /// transparent ones. Adjacency is 4-way, or 8-way if `diagonal` is set. Pixels
+    /// beyond the bitmap edges are not considered.
+    pub fn outline(&self, color: Rgba8, diagonal: bool, inside: bool) -> Self {
+        const ORTHOGONAL: [(i32, i32); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+        const DIAGONAL: [(i32, i32); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+        let neighbours = if diagonal {
+            &[ORTHOGONAL, DIAGONAL][..]
+        } else {
+            &[ORTHOGONAL][..]
+        };
+        let is_opaque = |x: i32, y: i32| {
+            if x >= 0 && y >= 0 && x < self.w as i32 && y < self.h as i32 {
+                Some(self.get(x as u32, y as u32).a > 0)
+            } else {
+                None
+            }
+        };
+
+        Self::from_fn(self.w, self.h, |x, y| {
+            let pixel = self.get(x, y);
+            let opaque = pixel.a > 0;
+
+            // Only pixels on the side of the outline are painted.
+            if opaque != inside {
+                return pixel;
+            }
+            let (x, y) = (x as i32, y as i32);
+            let border = neighbours
+                .iter()
+                .flatten()
+                .any(|(dx, dy)| is_opaque(x + dx, y + dy) == Some(!opaque));
+
+            if border {
+                color
+            } else {
+                pixel
+            }
+        })
+    }
+
+    /// Shift the bitmap contents right and up by the given amounts, wrapping
+    /// around the edges.
+    pub fn shift(&self, dx: i32, dy: i32) -> Self {
+        let (w, h) = (self.w as i32, self.h as i32);
+
+        Self::from_fn(self.w, self.h, |x, y| {
+            self.get(
+                (x as i32 - dx).rem_euclid(w) as u32,
+                (y as i32 + dy).rem_euclid(h) as u32,
+            )
+        })
+    }
+
+    /// Rotate the bitmap clockwise by the given number of quarter turns.
+    pub fn rotate(&self, quarter_turns: u32) -> Self {
+        match quarter_turns % 4 {
+            1 => Self::from_fn(self.h, self.w, |x, y| self.get(y, self.h - x - 1)),
+            2 => Self::from_fn(self.w, self.h, |x, y| {
+                self.get(self.w - x - 1, self.h - y - 1)
+            }),
+            3 => Self::from_fn(self.h, self.w, |x, y| self.get(self.w - y - 1, x)),
+            _ => self.clone(),
+        }
+    }
+
+    /// Rotate the bitmap clockwise by an arbitrary angle, using the RotSprite
+    /// algorithm: the bitmap is upscaled eight times with Scale2x, rotated, and
+    /// sampled back down, so that no new colors are introduced and edges stay
+    /// clean. The result is large enough to fit the rotated bitmap.
+    ///
+    /// The upscaled copy uses 64 times the memory of the bitmap, so large bitmaps
+    /// should be checked against [`Bitmap::ROTSPRITE_UPSCALE`] first.
+    pub fn rotsprite(&self, degrees: f32) -> Self {
+        const UPSCALE: f32 = Bitmap::ROTSPRITE_UPSCALE as f32;
+
+        let big = self.scale2x().scale2x().scale2x();
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (w, h) = (self.w as f32, self.h as f32);
+        let rw = (w * cos.abs() + h * sin.abs() - 0.001).ceil().max(1.);
+        let rh = (w * sin.abs() + h * cos.abs() - 0.001).ceil().max(1.);
+
+        Self::from_fn(rw as u32, rh as u32, |x, y| {
+            // Map the center of the output pixel back onto the source.
+            let (dx, dy) = (x as f32 + 0.5 - rw / 2., y as f32 + 0.5 - rh / 2.);
+            let sx = dx * cos + dy * sin + w / 2.;
+            let sy = -dx * sin + dy * cos + h / 2.;
+
+            if sx < 0. || sy < 0. || sx >= w || sy >= h {
+                Rgba8::TRANSPARENT
+            } else {
+                big.get(
+                    ((sx * UPSCALE) as u32).min(big.w - 1),
+                    ((sy * UPSCALE) as u32).min(big.h - 1),
+                )
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a bitmap from rows of pixels, top to bottom, where each character
+    /// stands for a color.
+    fn bitmap(rows: &[&str]) -> Bitmap {
+        let (w, h) = (rows[0].len() as u32, rows.len() as u32);
+
+        Bitmap::from_fn(w, h, |x, y| match rows[y as usize].as_bytes()[x as usize] {
+            b'.' => Rgba8::TRANSPARENT,
+            b'k' => Rgba8::BLACK,
+            b'w' => Rgba8::WHITE,
+            b'r' => Rgba8::RED,
+            b'g' => Rgba8::GREEN,
+            b'b' => Rgba8::BLUE,
+            c => panic!("unknown pixel `{}`", c as char),
+        })
+    }
+
+    #[test]
+    fn test_flip() {
+        let b = bitmap(&["rgb", "kw."]);
+
+        assert_eq!(b.flip_x(), bitmap(&["bgr", ".wk"]));
+        assert_eq!(b.flip_y(), bitmap(&["kw.", "rgb"]));
+        assert_eq!(b.flip_x().flip_x(), b);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let b = bitmap(&["rgb", "kw."]);
+
+        assert_eq!(b.rotate(0), b);
+        assert_eq!(b.rotate(1), bitmap(&["kr", "wg", ".b"]));
+        assert_eq!(b.rotate(2), bitmap(&[".wk", "bgr"]));
+        assert_eq!(b.rotate(3), bitmap(&["b.", "gw", "rk"]));
+        assert_eq!(b.rotate(4), b);
+        assert_eq!(b.rotate(1).rotate(1).rotate(1).rotate(1), b);
+    }
+
+    #[test]
+    fn test_scale() {
+        let b = bitmap(&["rg", "bk"]);
+
+        assert_eq!(
+            b.scale(2., 2., false),
+            bitmap(&["rrgg", "rrgg", "bbkk", "bbkk"])
+        );
+        assert_eq!(b.scale(2., 1., false), bitmap(&["rrgg", "bbkk"]));
+        assert_eq!(b.scale(2., 2., false).scale(0.5, 0.5, false), b);
+        // Bitmaps are never scaled down to nothing.
+        assert_eq!(b.scale(0.1, 0.1, false), bitmap(&["k"]));
+        // The size can be known without scaling.
+        assert_eq!(Bitmap::scaled_size(2, 2, 1e9, 0.1), (2_000_000_000, 1));
+        assert_eq!(Bitmap::scaled_size(3, 2, 1.5, 2.), (5, 4));
+    }
+
+    #[test]
+    fn test_scale_epx() {
+        let b = bitmap(&["kk", "k."]);
+
+        // The diagonal edge is smoothed, instead of being scaled into a step.
+        assert_eq!(b.scale2x(), bitmap(&["kkkk", "kkkk", "kkk.", "kk.."]));
+        assert_eq!(b.scale(2., 2., true), b.scale2x());
+        assert_eq!(b.scale(3., 3., true), b.scale3x());
+        assert_eq!(b.scale3x().w, 6);
+        assert_eq!(b.scale3x().h, 6);
+
+        // Without any edges, EPX is the same as nearest-neighbour.
+        let solid = Bitmap::filled(2, 2, Rgba8::RED);
+        assert_eq!(solid.scale2x(), solid.scale(2., 2., false));
+        assert_eq!(solid.scale3x(), solid.scale(3., 3., false));
+    }
+
+    #[test]
+    fn test_rotsprite() {
+        let b = bitmap(&["rgb", "kw."]);
+
+        assert_eq!(b.rotsprite(0.), b);
+        assert_eq!(b.rotsprite(90.), b.rotate(1));
+        assert_eq!(b.rotsprite(180.), b.rotate(2));
+        assert_eq!(b.rotsprite(270.), b.rotate(3));
+
+        // The result fits the rotated bitmap, and no new colors are introduced.
+        let r = b.rotsprite(45.);
+        assert_eq!((r.w, r.h), (4, 4));
+        assert!(r
+            .pixels
+            .iter()
+            .all(|p| *p == Rgba8::TRANSPARENT || b.pixels.contains(p)));
+    }
+
+    #[test]
+    fn test_outline() {
+        let dot = bitmap(&[".....", "..k..", "....."]);
+
+        assert_eq!(
+            dot.outline(Rgba8::RED, false, false),
+            bitmap(&["..r..", ".rkr.", "..r.."])
+        );
+        assert_eq!(
+            dot.outline(Rgba8::RED, true, false),
+            bitmap(&[".rrr.", ".rkr.", ".rrr."])
+        );
+
+        let square = bitmap(&[".....", ".kkk.", ".kkk.", ".kkk.", "....."]);
+        assert_eq!(
+            square.outline(Rgba8::RED, false, true),
+            bitmap(&[".....", ".rrr.", ".rkr.", ".rrr.", "....."])
+        );
+
+        // Pixels beyond the edges don't count as transparent.
+        let full = bitmap(&["kk", "kk"]);
+        assert_eq!(full.outline(Rgba8::RED, true, true), full);
+        assert_eq!(full.outline(Rgba8::RED, true, false), full);
+    }
+}
@@ -36,6 +36,24 @@ pub enum BrushMode {
     YSym,
     /// X-Ray mode.
     XRay,
+    /// Wrap-around mode. Strokes leaving a frame continue on the opposite edge.
+    Wrap,
+    /// Alpha-lock mode. Only pixels which aren't transparent are painted on.
+    AlphaLock,
+}
+
+impl BrushMode {
+    /// All brush modes.
+    pub const ALL: &'static [BrushMode] = &[
+        Self::Erase,
+        Self::Multi,
+        Self::Perfect,
+        Self::XSym,
+        Self::YSym,
+        Self::XRay,
+        Self::Wrap,
+        Self::AlphaLock,
+    ];
 }
 
 impl fmt::Display for BrushMode {
@@ -47,6 +65,8 @@ impl fmt::Display for BrushMode {
             Self::XSym => "xsym".fmt(f),
             Self::YSym => "ysym".fmt(f),
             Self::XRay => "xray".fmt(f),
+            Self::Wrap => "wrap".fmt(f),
+            Self::AlphaLock => "alpha-lock".fmt(f),
         }
     }
 }
@@ -176,8 +196,12 @@ impl Brush {
 
     /// Expand a point into all brush heads.
     pub fn expand(&self, p: ViewCoords<i32>, extent: ViewExtent) -> Vec<ViewCoords<i32>> {
-        let mut pixels = vec![*p];
         let ViewExtent { fw, fh, nframes } = extent;
+        let mut pixels = if self.is_set(BrushMode::Wrap) {
+            vec![self.wrap(*p, extent)]
+        } else {
+            vec![*p]
+        };
 
         if self.is_set(BrushMode::XSym) {
             for p in pixels.clone() {
@@ -206,6 +230,30 @@ impl Brush {
         pixels.iter().map(|p| ViewCoords::new(p.x, p.y)).collect()
     }
 
+    /// Return every pixel covered by the brush's output strokes. Unlike
+    /// [`Brush::output`], brush heads are split into individual pixels.
+    pub fn pixels(&self) -> Vec<Point2<i32>> {
+        match self.state {
+            BrushState::DrawStarted(extent)
+            | BrushState::Drawing(extent)
+            | BrushState::DrawEnded(extent) => {
+                let mut pixels = Vec::new();
+
+                for p in self.stroke.iter().flat_map(|p| self.area(*p)) {
+                    pixels.extend(
+                        self.expand(ViewCoords::new(p.x, p.y), extent)
+                            .iter()
+                            .map(|p| **p),
+                    );
+                }
+                pixels.sort_by_key(|p| (p.y, p.x));
+                pixels.dedup();
+                pixels
+            }
+            _ => Vec::new(),
+        }
+    }
+
     /// Return the brush's output strokes as shapes.
     pub fn output(&self, stroke: Stroke, fill: Fill, scale: f32, origin: Origin) -> Vec<Shape> {
         match self.state {
@@ -214,7 +262,16 @@ impl Brush {
             | BrushState::DrawEnded(extent) => {
                 let mut pixels = Vec::new();
 
-                for p in &self.stroke {
+                // In wrap mode, brush heads are split into individual pixels,
+                // so that the parts of a head crossing a frame edge can be
+                // wrapped independently.
+                let (heads, size) = if self.is_set(BrushMode::Wrap) && self.size > 1 {
+                    (self.stroke.iter().flat_map(|p| self.area(*p)).collect(), 1)
+                } else {
+                    (self.stroke.clone(), self.size)
+                };
+
+                for p in &heads {
                     pixels.extend_from_slice(
                         self.expand(ViewCoords::new(p.x, p.y), extent).as_slice(),
                     );
@@ -222,8 +279,9 @@ impl Brush {
                 pixels
                     .iter()
                     .map(|p| {
-                        self.shape(
+                        Self::rectangle(
                             Point2::new(p.x as f32, p.y as f32),
+                            size,
                             ZDepth::ZERO,
                             stroke,
                             fill,
@@ -249,17 +307,31 @@ impl Brush {
         fill: Fill,
         scale: f32,
         origin: Origin,
+    ) -> Shape {
+        Self::rectangle(p, self.size, z, stroke, fill, scale, origin)
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+
+    /// Return a square shape of the given size at the given position.
+    fn rectangle(
+        p: Point2<f32>,
+        size: usize,
+        z: ZDepth,
+        stroke: Stroke,
+        fill: Fill,
+        scale: f32,
+        origin: Origin,
     ) -> Shape {
         let x = p.x;
         let y = p.y;
 
-        let size = self.size as f32;
-
         let offset = match origin {
-            Origin::Center => size * scale / 2.,
-            Origin::BottomLeft => (self.size / 2) as f32 * scale,
+            Origin::Center => size as f32 * scale / 2.,
+            Origin::BottomLeft => (size / 2) as f32 * scale,
             Origin::TopLeft => unreachable!(),
         };
+        let size = size as f32;
 
         Shape::Rectangle(
             Rect::new(x, y, x + size * scale, y + size * scale) - Vector2::new(offset, offset),
@@ -270,10 +342,38 @@ impl Brush {
         )
     }
 
-    ///////////////////////////////////////////////////////////////////////////
+    /// Wrap a point around the edges of the frame the current stroke started
+    /// in, or the frame the point is in if we aren't drawing.
+    fn wrap(&self, p: Point2<i32>, extent: ViewExtent) -> Point2<i32> {
+        let (fw, fh) = (extent.fw as i32, extent.fh as i32);
+        let origin = self.stroke.first().cloned().unwrap_or(p);
+        let frame = i32::max(
+            0,
+            i32::min(origin.x.div_euclid(fw), extent.nframes as i32 - 1),
+        );
+
+        Point2::new(
+            frame * fw + (p.x - frame * fw).rem_euclid(fw),
+            p.y.rem_euclid(fh),
+        )
+    }
+
+    /// Return all the pixels covered by a brush head at the given position.
+    fn area(&self, p: Point2<i32>) -> Vec<Point2<i32>> {
+        let size = self.size as i32;
+        let offset = size / 2;
+        let mut pixels = Vec::with_capacity(self.size * self.size);
+
+        for y in 0..size {
+            for x in 0..size {
+                pixels.push(Point2::new(p.x - offset + x, p.y - offset + y));
+            }
+        }
+        pixels
+    }
 
     /// Draw a line between two points. Uses Bresenham's line algorithm.
-    fn line(mut p0: Point2<i32>, p1: Point2<i32>, canvas: &mut Vec<Point2<i32>>) {
+    pub fn line(mut p0: Point2<i32>, p1: Point2<i32>, canvas: &mut Vec<Point2<i32>>) {
         let dx = i32::abs(p1.x - p0.x);
         let dy = i32::abs(p1.y - p0.y);
         let sx = if p0.x < p1.x { 1 } else { -1 };
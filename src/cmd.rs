@@ -1,16 +1,24 @@
 use crate::brush::{Brush, BrushMode};
-use crate::parser::{Error, Parse, Parser, Result};
+use crate::color::Blend;
+use crate::gradient::GradientShape;
+use crate::parser::{Error, Parse, Parser, Result, Scope};
 use crate::platform;
-use crate::session::{Direction, Mode, PanState, Tool, VisualState};
+use crate::session::{
+    Axis, Direction, GradientState, Mode, PanState, SelectionTool, Tool, VisualState,
+};
 
 use rgx::core::Rect;
 use rgx::kit::Rgba8;
 
 use std::fmt;
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::Path;
 use std::result;
 use std::str::FromStr;
 
 pub const COMMENT: char = '-';
+pub const SEPARATOR: char = '|';
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum Op {
@@ -31,13 +39,24 @@ pub enum Command {
     BrushUnset(BrushMode),
     #[allow(dead_code)]
     Crop(Rect<u32>),
-    Echo(Value),
+    Call(String, Vec<String>),
+    Count(u32, Box<Command>),
+    Def(String, String),
+    DefList,
+    Deferred(String, Vec<String>),
+    Else,
+    End,
+    Echo(Expr),
     Edit(Vec<String>),
     Fill(Rgba8),
+    Gradient((i32, i32), (i32, i32)),
+    History(usize),
+    If(Expr),
     ForceQuit,
     ForceQuitAll,
     Map(Box<KeyMapping>),
     MapClear,
+    Let(String, Expr),
     Mode(Mode),
     AddFrame,
     CloneFrame(i32),
@@ -46,23 +65,34 @@ pub enum Command {
     PaletteAdd(Rgba8),
     PaletteClear,
     PaletteSample,
+    Outline(Option<Rgba8>),
     Pan(i32, i32),
     Quit,
     QuitAll,
     Reset,
     Redo,
+    Registers,
+    Repeat,
     ResizeFrame(u32, u32),
+    Seq(Vec<Command>),
     SelectionMove(i32, i32),
+    SelectionShift(i32, i32),
+    SelectionFlip(Axis),
+    SelectionRotate(f32),
+    SelectionScale(f32, f32),
     SelectionResize(i32, i32),
+    SelectionCell(u32, u32),
     SelectionOffset(i32, i32),
     SelectionExpand,
-    SelectionPaste,
-    SelectionYank,
-    SelectionCut,
+    SelectionPaste(Option<char>),
+    SelectionYank(Option<char>),
+    SelectionWrite(String),
+    SelectionNew,
+    SelectionCut(Option<char>),
     SelectionFill(Option<Rgba8>),
     SelectionErase,
     SelectionJump(Direction),
-    Set(String, Value),
+    Set(String, Expr),
     Slice(Option<usize>),
     Source(String),
     SwapColors,
@@ -73,12 +103,16 @@ pub enum Command {
     ViewCenter,
     ViewNext,
     ViewPrev,
+    ViewScale(f32, f32),
     Write(Option<String>),
     WriteQuit,
     Zoom(Op),
 }
 
 impl Command {
+    /// The largest number of times a command can be repeated with a count.
+    pub const MAX_COUNT: u32 = 999;
+
     pub fn repeats(&self) -> bool {
         match self {
             Self::Zoom(_)
@@ -92,9 +126,37 @@ impl Command {
             | Self::SelectionJump(_)
             | Self::SelectionResize(_, _)
             | Self::SelectionOffset(_, _) => true,
+            Self::Count(_, cmd) => cmd.repeats(),
+            Self::Repeat => true,
             _ => false,
         }
     }
+
+    /// Whether this command edits the view, and can be run again with `:repeat`.
+    /// Moving the selection is also an edit when it moves floating pixels. Pixels
+    /// painted with tools, or lifted and dropped with the mouse, aren't edited
+    /// by commands, and can't be repeated.
+    pub fn is_edit(&self) -> bool {
+        matches!(
+            self,
+            Self::Fill(_)
+                | Self::Gradient(..)
+                | Self::Outline(_)
+                | Self::AddFrame
+                | Self::CloneFrame(_)
+                | Self::RemoveFrame
+                | Self::ResizeFrame(_, _)
+                | Self::ViewScale(_, _)
+                | Self::SelectionShift(_, _)
+                | Self::SelectionFlip(_)
+                | Self::SelectionRotate(_)
+                | Self::SelectionScale(_, _)
+                | Self::SelectionPaste(_)
+                | Self::SelectionCut(_)
+                | Self::SelectionFill(_)
+                | Self::SelectionErase
+        )
+    }
 }
 
 impl fmt::Display for Command {
@@ -111,6 +173,8 @@ impl fmt::Display for Command {
             Self::Echo(_) => write!(f, "Echo a value"),
             Self::Edit(_) => write!(f, "Edit path(s)"),
             Self::Fill(c) => write!(f, "Fill view with {color}", color = c),
+            Self::Gradient(..) => write!(f, "Fill with a gradient"),
+            Self::History(n) => write!(f, "List the last {} commands", n),
             Self::ForceQuit => write!(f, "Quit view without saving"),
             Self::ForceQuitAll => write!(f, "Quit all views without saving"),
             Self::Map(_) => write!(f, "Map a key combination to a command"),
@@ -135,6 +199,7 @@ impl fmt::Display for Command {
             Self::Tool(Tool::Pan(_)) => write!(f, "Pan tool"),
             Self::Tool(Tool::Brush(_)) => write!(f, "Brush tool"),
             Self::Tool(Tool::Sampler) => write!(f, "Color sampler tool"),
+            Self::Tool(Tool::Gradient(_)) => write!(f, "Gradient tool"),
             Self::ToolPrev => write!(f, "Switch to previous tool"),
             Self::Set(s, v) => write!(f, "Set {setting} to {val}", setting = s, val = v),
             Self::Slice(Some(n)) => write!(f, "Slice view into {} frame(s)", n),
@@ -154,10 +219,39 @@ impl fmt::Display for Command {
             Self::Zoom(Op::Set(z)) => write!(f, "Set view zoom to {:.1}", z),
             Self::Reset => write!(f, "Reset all settings to default"),
             Self::SelectionFill(None) => write!(f, "Fill selection with foreground color"),
-            Self::SelectionYank => write!(f, "Yank (copy) selection"),
-            Self::SelectionCut => write!(f, "Cut selection"),
-            Self::SelectionPaste => write!(f, "Paste selection"),
+            Self::SelectionYank(None) => write!(f, "Yank (copy) selection"),
+            Self::SelectionYank(Some(r)) => write!(f, "Yank (copy) selection into register {}", r),
+            Self::SelectionCut(None) => write!(f, "Cut selection"),
+            Self::SelectionCut(Some(r)) => write!(f, "Cut selection into register {}", r),
+            Self::SelectionPaste(None) => write!(f, "Paste selection"),
+            Self::SelectionPaste(Some(r)) => write!(f, "Paste from register {}", r),
+            Self::Registers => write!(f, "List yank registers"),
+            Self::Repeat => write!(f, "Repeat the last edit"),
+            Self::Call(name, _) => write!(f, "Run :{}", name),
+            Self::Count(n, cmd) => write!(f, "{} ({} times)", cmd, n),
+            Self::Def(name, _) => write!(f, "Define command :{}", name),
+            Self::DefList => write!(f, "List user-defined commands"),
+            Self::Deferred(body, _) => write!(f, "Run `{}`", body),
+            Self::Let(name, e) => write!(f, "Set ${} to {}", name, e),
+            Self::If(e) => write!(f, "Run the following commands if {}", e),
+            Self::Else => write!(f, "Run the following commands otherwise"),
+            Self::End => write!(f, "End conditional block"),
+            Self::Seq(cmds) => {
+                for (i, cmd) in cmds.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", cmd)?;
+                }
+                Ok(())
+            }
+            Self::SelectionCell(x, y) => write!(f, "Select grid cell {},{}", x, y),
+            Self::Outline(None) => write!(f, "Outline with foreground color"),
+            Self::Outline(Some(c)) => write!(f, "Outline with {}", c),
+            Self::SelectionWrite(_) => write!(f, "Write selection to disk as..."),
+            Self::SelectionNew => write!(f, "Open selection in a new view"),
             Self::SelectionExpand => write!(f, "Expand selection to frame"),
+            Self::SelectionShift(x, y) => write!(f, "Shift selected pixels by {},{}", x, y),
             Self::SelectionOffset(1, 1) => write!(f, "Outset selection"),
             Self::SelectionOffset(-1, -1) => write!(f, "Inset selection"),
             Self::SelectionOffset(x, y) => write!(f, "Offset selection by {:2},{:2}", x, y),
@@ -165,6 +259,10 @@ impl fmt::Display for Command {
             Self::SelectionMove(x, 0) if *x < 0 => write!(f, "Move selection left"),
             Self::SelectionMove(0, y) if *y > 0 => write!(f, "Move selection up"),
             Self::SelectionMove(0, y) if *y < 0 => write!(f, "Move selection down"),
+            Self::SelectionFlip(axis) => write!(f, "Flip selection on the {} axis", axis),
+            Self::SelectionRotate(deg) => write!(f, "Rotate selection by {} degrees", deg),
+            Self::SelectionScale(x, y) => write!(f, "Scale selection by {}x{}", x, y),
+            Self::ViewScale(x, y) => write!(f, "Scale view by {}x{}", x, y),
             Self::SelectionJump(Direction::Forward) => {
                 write!(f, "Move selection forward by one frame")
             }
@@ -234,7 +332,10 @@ impl Key {
     pub fn is_modifier(self) -> bool {
         let Self::Virtual(key) = self;
         match key {
-            platform::Key::Alt | platform::Key::Control | platform::Key::Shift => true,
+            platform::Key::Alt
+            | platform::Key::Control
+            | platform::Key::Shift
+            | platform::Key::Meta => true,
             _ => false,
         }
     }
@@ -261,12 +362,65 @@ impl<'a> Parse<'a> for Key {
                 "ctrl" => platform::Key::Control,
                 "alt" => platform::Key::Alt,
                 "shift" => platform::Key::Shift,
+                "meta" => platform::Key::Meta,
                 "space" => platform::Key::Space,
                 "return" => platform::Key::Return,
                 "backspace" => platform::Key::Backspace,
                 "tab" => platform::Key::Tab,
                 "end" => platform::Key::End,
                 "esc" => platform::Key::Escape,
+                "home" => platform::Key::Home,
+                "pgup" => platform::Key::PageUp,
+                "pgdown" => platform::Key::PageDown,
+                "insert" => platform::Key::Insert,
+                "delete" => platform::Key::Delete,
+                "f1" => platform::Key::F1,
+                "f2" => platform::Key::F2,
+                "f3" => platform::Key::F3,
+                "f4" => platform::Key::F4,
+                "f5" => platform::Key::F5,
+                "f6" => platform::Key::F6,
+                "f7" => platform::Key::F7,
+                "f8" => platform::Key::F8,
+                "f9" => platform::Key::F9,
+                "f10" => platform::Key::F10,
+                "f11" => platform::Key::F11,
+                "f12" => platform::Key::F12,
+                "f13" => platform::Key::F13,
+                "f14" => platform::Key::F14,
+                "f15" => platform::Key::F15,
+                "f16" => platform::Key::F16,
+                "f17" => platform::Key::F17,
+                "f18" => platform::Key::F18,
+                "f19" => platform::Key::F19,
+                "f20" => platform::Key::F20,
+                "f21" => platform::Key::F21,
+                "f22" => platform::Key::F22,
+                "f23" => platform::Key::F23,
+                "f24" => platform::Key::F24,
+                "k0" => platform::Key::Kp0,
+                "k1" => platform::Key::Kp1,
+                "k2" => platform::Key::Kp2,
+                "k3" => platform::Key::Kp3,
+                "k4" => platform::Key::Kp4,
+                "k5" => platform::Key::Kp5,
+                "k6" => platform::Key::Kp6,
+                "k7" => platform::Key::Kp7,
+                "k8" => platform::Key::Kp8,
+                "k9" => platform::Key::Kp9,
+                "kplus" => platform::Key::KpAdd,
+                "kminus" => platform::Key::KpSubtract,
+                "kmultiply" => platform::Key::KpMultiply,
+                "kdivide" => platform::Key::KpDivide,
+                "kpoint" => platform::Key::KpDecimal,
+                "kenter" => platform::Key::KpEnter,
+                "kequal" => platform::Key::KpEqual,
+                "capslock" => platform::Key::CapsLock,
+                "numlock" => platform::Key::NumLock,
+                "scrolllock" => platform::Key::ScrollLock,
+                "printscreen" => platform::Key::PrintScreen,
+                "pause" => platform::Key::Pause,
+                "menu" => platform::Key::Menu,
                 other => return Err(Error::new(format!("unknown key <{}>", other))),
             };
             Ok((Key::Virtual(virt), p))
@@ -277,11 +431,40 @@ impl<'a> Parse<'a> for Key {
     }
 }
 
+/// Modifier keys held while pressing a key, eg. `<ctrl><shift>` in `<ctrl><shift>z`.
+/// A modifier key which isn't directly followed by another key, eg. `<ctrl>`, is
+/// not parsed as a modifier, but as a key.
+impl<'a> Parse<'a> for platform::ModifiersState {
+    fn parse(p: Parser<'a>) -> Result<'a, Self> {
+        let mut modifiers = platform::ModifiersState::default();
+        let mut p = p;
+
+        while let Ok((Key::Virtual(key), q)) = p.clone().parse::<Key>() {
+            if q.peek().map_or(true, char::is_whitespace) {
+                break;
+            }
+            match key {
+                platform::Key::Control => modifiers.ctrl = true,
+                platform::Key::Alt => modifiers.alt = true,
+                platform::Key::Shift => modifiers.shift = true,
+                platform::Key::Meta => modifiers.meta = true,
+                _ => break,
+            }
+            p = q;
+        }
+        Ok((modifiers, p))
+    }
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
 #[derive(Debug, Clone)]
 pub struct KeyMapping {
     pub key: Key,
+    pub modifiers: platform::ModifiersState,
+    /// Keys which must be pressed before `key`, with their modifiers, eg. the
+    /// first `g` in `g g`.
+    pub sequence: Vec<(platform::ModifiersState, Key)>,
     pub press: Command,
     pub release: Option<Command>,
     pub modes: Vec<Mode>,
@@ -291,13 +474,30 @@ impl KeyMapping {
     fn parse<'a>(p: Parser<'a>, modes: &[Mode]) -> Result<'a, Self> {
         let modes = modes.to_vec();
 
-        let (key, p) = p.parse::<Key>()?;
+        // Keys are pressed in sequence until the command, eg. `g g :v/center`.
+        let (combo, mut p) = Self::combo(p)?;
+        let mut sequence = vec![combo];
+
+        loop {
+            let (_, q) = p.clone().whitespace()?;
+            if q.is_empty() || q.peek() == Some(':') {
+                break;
+            }
+            let (combo, q) = Self::combo(q)?;
+            sequence.push(combo);
+            p = q;
+        }
+        let (modifiers, key) = sequence.pop().expect("there is at least one key");
+
         let (_, p) = p.whitespace()?;
-        let (press, p) = p.parse::<Command>()?;
+        let (press, p) = Self::body(p)?;
         let (_, p) = p.whitespace()?;
 
         let (release, p) = if let Ok((_, p)) = p.clone().sigil('{') {
-            let (cmd, p) = p.parse::<Command>()?;
+            if !sequence.is_empty() {
+                return Err(Error::new("key sequences can't have a release command"));
+            }
+            let (cmd, p) = Self::body(p)?;
             let (_, p) = p.sigil('}')?;
             (Some(cmd), p)
         } else {
@@ -306,6 +506,8 @@ impl KeyMapping {
         Ok((
             KeyMapping {
                 key,
+                modifiers,
+                sequence,
                 press,
                 release,
                 modes,
@@ -313,6 +515,41 @@ impl KeyMapping {
             p,
         ))
     }
+
+    /// Parse the command run by a mapping. Since `$` references are substituted
+    /// as commands are parsed, a command with references is parsed again when
+    /// it's run, so that it uses the values they have at that time.
+    fn body(p: Parser<'_>) -> Result<'_, Command> {
+        let (input, _) = p.clone().leftover()?;
+        let (cmd, p) = p.parse::<Command>()?;
+        let (rest, _) = p.clone().leftover()?;
+        let body = input[..input.len() - rest.len()].trim_end();
+
+        match p.scope() {
+            Some(scope) if scope.vars.is_some() && body.contains('$') => {
+                Ok((Command::Deferred(body.to_owned(), scope.args.clone()), p))
+            }
+            _ => Ok((cmd, p)),
+        }
+    }
+
+    /// Parse a key along with the modifiers held, eg. `<ctrl><shift>z`.
+    fn combo(p: Parser<'_>) -> Result<'_, (platform::ModifiersState, Key)> {
+        let (modifiers, p) = p.parse::<platform::ModifiersState>()?;
+        let (key, p) = p.parse::<Key>()?;
+
+        Ok(((modifiers, key), p))
+    }
+
+    /// How the keys of this mapping are displayed, eg. `<ctrl>z` or `g g`.
+    pub fn display(&self) -> String {
+        self.sequence
+            .iter()
+            .chain(std::iter::once(&(self.modifiers, self.key)))
+            .map(|(m, k)| format!("{}{}", m, k))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -328,6 +565,9 @@ pub enum Value {
     Str(String),
     Ident(String),
     Rgba8(Rgba8),
+    Blend(Blend),
+    GradientShape(GradientShape),
+    SelectionTool(SelectionTool),
 }
 
 impl Value {
@@ -352,6 +592,39 @@ impl Value {
         panic!("expected {:?} to be a `uint`", self);
     }
 
+    pub fn blend(&self) -> Blend {
+        if let Value::Blend(b) = self {
+            return *b;
+        }
+        panic!("expected {:?} to be a `Blend`", self);
+    }
+
+    pub fn gradient_shape(&self) -> GradientShape {
+        if let Value::GradientShape(g) = self {
+            return *g;
+        }
+        panic!("expected {:?} to be a `GradientShape`", self);
+    }
+
+    pub fn selection_tool(&self) -> SelectionTool {
+        if let Value::SelectionTool(t) = self {
+            return *t;
+        }
+        panic!("expected {:?} to be a `SelectionTool`", self);
+    }
+
+    /// Convert a value to the type of `target`. Settings which hold one of a set
+    /// of names, such as blend modes, are given as identifiers and parsed here,
+    /// once, when they are set. Other values are returned as-is.
+    pub fn convert(self, target: &Value) -> result::Result<Value, String> {
+        match (target, self) {
+            (Value::Blend(_), Value::Ident(s)) => s.parse().map(Value::Blend),
+            (Value::GradientShape(_), Value::Ident(s)) => s.parse().map(Value::GradientShape),
+            (Value::SelectionTool(_), Value::Ident(s)) => s.parse().map(Value::SelectionTool),
+            (_, v) => Ok(v),
+        }
+    }
+
     pub fn rgba8(&self) -> Rgba8 {
         if let Value::Rgba8(rgba8) = self {
             return *rgba8;
@@ -359,6 +632,123 @@ impl Value {
         panic!("expected {:?} to be a `Rgba8`", self);
     }
 
+    /// Whether the value counts as true in a condition.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Self::Bool(b) => *b,
+            Self::U32(n) => *n != 0,
+            Self::F32(n) => *n != 0.,
+            Self::Str(s) => !s.is_empty(),
+            _ => true,
+        }
+    }
+
+    /// The value as a number, if it is one.
+    fn number(&self) -> Option<f64> {
+        match self {
+            Self::U32(n) => Some(*n as f64),
+            Self::F32(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Apply a binary operator to two values. Numbers can be combined with each
+    /// other, colors can be added and subtracted channel by channel, or have
+    /// their color channels multiplied or divided by a number, and strings can
+    /// be concatenated. Any two values can be compared for equality.
+    pub fn apply(&self, op: BinOp, other: &Value) -> result::Result<Value, String> {
+        let unsupported = || format!("can't apply `{}` to `{}` and `{}`", op, self, other);
+
+        match op {
+            BinOp::Eq | BinOp::Ne => {
+                let eq = match (self.number(), other.number()) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => self == other,
+                };
+                return Ok(Value::Bool(eq == (op == BinOp::Eq)));
+            }
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                let (a, b) = self.number().zip(other.number()).ok_or_else(unsupported)?;
+                return Ok(Value::Bool(match op {
+                    BinOp::Lt => a < b,
+                    BinOp::Le => a <= b,
+                    BinOp::Gt => a > b,
+                    _ => a >= b,
+                }));
+            }
+            _ => {}
+        }
+
+        match (self, other) {
+            (Value::U32(a), Value::U32(b)) => match op {
+                BinOp::Add => a
+                    .checked_add(*b)
+                    .map(Value::U32)
+                    .ok_or_else(|| "overflow".into()),
+                BinOp::Sub => a
+                    .checked_sub(*b)
+                    .map(Value::U32)
+                    .ok_or_else(|| format!("`{} - {}` is negative", a, b)),
+                BinOp::Mul => a
+                    .checked_mul(*b)
+                    .map(Value::U32)
+                    .ok_or_else(|| "overflow".into()),
+                _ => a
+                    .checked_div(*b)
+                    .map(Value::U32)
+                    .ok_or_else(|| "division by zero".into()),
+            },
+            (Value::U32(_), Value::F32(_))
+            | (Value::F32(_), Value::U32(_))
+            | (Value::F32(_), Value::F32(_)) => {
+                let (a, b) = self.number().zip(other.number()).ok_or_else(unsupported)?;
+                match op {
+                    BinOp::Add => Ok(Value::F32(a + b)),
+                    BinOp::Sub => Ok(Value::F32(a - b)),
+                    BinOp::Mul => Ok(Value::F32(a * b)),
+                    _ if b == 0. => Err("division by zero".into()),
+                    _ => Ok(Value::F32(a / b)),
+                }
+            }
+            (Value::Rgba8(a), Value::Rgba8(b)) => match op {
+                BinOp::Add => Ok(Value::Rgba8(Rgba8::new(
+                    a.r.saturating_add(b.r),
+                    a.g.saturating_add(b.g),
+                    a.b.saturating_add(b.b),
+                    a.a.saturating_add(b.a),
+                ))),
+                BinOp::Sub => Ok(Value::Rgba8(Rgba8::new(
+                    a.r.saturating_sub(b.r),
+                    a.g.saturating_sub(b.g),
+                    a.b.saturating_sub(b.b),
+                    a.a,
+                ))),
+                _ => Err(unsupported()),
+            },
+            (Value::Rgba8(c), n) if n.number().is_some() => {
+                let n = n.number().unwrap_or(1.);
+                let factor = match op {
+                    BinOp::Mul => n,
+                    BinOp::Div if n != 0. => 1. / n,
+                    BinOp::Div => return Err("division by zero".into()),
+                    _ => return Err(unsupported()),
+                };
+                let scale = |c: u8| (c as f64 * factor).round().clamp(0., 255.) as u8;
+
+                Ok(Value::Rgba8(Rgba8::new(
+                    scale(c.r),
+                    scale(c.g),
+                    scale(c.b),
+                    c.a,
+                )))
+            }
+            (Value::Str(a), Value::Str(b)) if op == BinOp::Add => {
+                Ok(Value::Str(format!("{}{}", a, b)))
+            }
+            _ => Err(unsupported()),
+        }
+    }
+
     pub fn description(&self) -> &'static str {
         match self {
             Self::Bool(_) => "on / off",
@@ -369,6 +759,9 @@ impl Value {
             Self::Str(_) => "string, eg. \"fnord\"",
             Self::Rgba8(_) => "color, eg. #ffff00",
             Self::Ident(_) => "identifier, eg. fnord",
+            Self::Blend(_) => "blend mode, eg. multiply",
+            Self::GradientShape(_) => "gradient shape, eg. radial",
+            Self::SelectionTool(_) => "selection tool, eg. lasso",
         }
     }
 }
@@ -400,6 +793,22 @@ impl Into<f64> for Value {
     }
 }
 
+impl Value {
+    /// Parse a number. Numbers are delimited explicitly, so that they can be
+    /// followed by a closing parenthesis in expressions.
+    fn parse_number(p: Parser<'_>) -> Result<'_, Self> {
+        let (s, p) = p.expect(|c| c.is_ascii_digit() || c == '.')?;
+
+        if let Ok(n) = u32::from_str(s) {
+            Ok((Value::U32(n), p))
+        } else if let Ok(n) = f64::from_str(s) {
+            Ok((Value::F32(n), p))
+        } else {
+            Err(Error::new(format!("malformed number: `{}`", s)))
+        }
+    }
+}
+
 impl<'a> Parse<'a> for Value {
     fn parse(p: Parser<'a>) -> Result<'a, Self> {
         let c = p.peek();
@@ -411,16 +820,18 @@ impl<'a> Parse<'a> for Value {
             let (v, p) = p.parse::<Rgba8>()?;
             Ok((Value::Rgba8(v), p))
         } else if c.map_or(false, |c| c.is_digit(10)) {
-            if let Ok(((x, y), p)) = p.clone().parse::<(u32, u32)>() {
-                Ok((Value::U32Tuple(x, y), p))
-            } else if let Ok((v, p)) = p.clone().parse::<u32>() {
-                Ok((Value::U32(v), p))
-            } else if let Ok((v, p)) = p.clone().parse::<f64>() {
-                Ok((Value::F32(v), p))
-            } else {
-                let (input, _) = p.until(|c| c.is_whitespace())?;
-                Err(Error::new(format!("malformed number: `{}`", input)))
+            let (v, p) = Value::parse_number(p)?;
+
+            if let Value::U32(x) = v {
+                if let Ok((Value::U32(y), p)) = p
+                    .clone()
+                    .whitespace()
+                    .and_then(|(_, p)| Value::parse_number(p))
+                {
+                    return Ok((Value::U32Tuple(x, y), p));
+                }
             }
+            Ok((v, p))
         } else {
             let (i, p) = p.identifier()?;
             match i {
@@ -444,22 +855,333 @@ impl fmt::Display for Value {
             Value::Str(s) => s.fmt(f),
             Value::Rgba8(c) => c.fmt(f),
             Value::Ident(i) => i.fmt(f),
+            Value::Blend(b) => b.fmt(f),
+            Value::GradientShape(g) => g.fmt(f),
+            Value::SelectionTool(t) => t.fmt(f),
         }
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// A binary operator, used in expressions.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinOp {
+    /// Operator precedence. Operators with higher precedence bind tighter.
+    fn precedence(self) -> u8 {
+        match self {
+            Self::Mul | Self::Div => 2,
+            Self::Add | Self::Sub => 1,
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Add => "+".fmt(f),
+            Self::Sub => "-".fmt(f),
+            Self::Mul => "*".fmt(f),
+            Self::Div => "/".fmt(f),
+            Self::Eq => "==".fmt(f),
+            Self::Ne => "!=".fmt(f),
+            Self::Lt => "<".fmt(f),
+            Self::Le => "<=".fmt(f),
+            Self::Gt => ">".fmt(f),
+            Self::Ge => ">=".fmt(f),
+        }
+    }
+}
+
+impl<'a> Parse<'a> for BinOp {
+    fn parse(p: Parser<'a>) -> Result<'a, Self> {
+        let c = match p.peek() {
+            Some(c @ ('+' | '-' | '*' | '/' | '=' | '!' | '<' | '>')) => c,
+            _ => return Err(Error::new("expected operator")),
+        };
+        let (_, p) = p.sigil(c)?;
+        let eq = p.peek() == Some('=');
+
+        match c {
+            // Two dashes start a comment.
+            '-' if p.peek() == Some('-') => Err(Error::new("expected operator")),
+            '+' => Ok((Self::Add, p)),
+            '-' => Ok((Self::Sub, p)),
+            '*' => Ok((Self::Mul, p)),
+            '/' => Ok((Self::Div, p)),
+            '=' if eq => Ok((Self::Eq, p.sigil('=')?.1)),
+            '!' if eq => Ok((Self::Ne, p.sigil('=')?.1)),
+            '<' if eq => Ok((Self::Le, p.sigil('=')?.1)),
+            '>' if eq => Ok((Self::Ge, p.sigil('=')?.1)),
+            '<' => Ok((Self::Lt, p)),
+            '>' => Ok((Self::Gt, p)),
+            _ => Err(Error::new(format!("unknown operator `{}`", c))),
+        }
+    }
+}
+
+/// An expression, which is evaluated to a value when the command it's part of
+/// is run, eg. `$size * 2`. Variables and settings are referenced with `$`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expr {
+    /// A literal value.
+    Value(Value),
+    /// A variable or setting.
+    Var(String),
+    /// A binary operation.
+    Binary(Box<Expr>, BinOp, Box<Expr>),
+}
+
+impl Expr {
+    fn parse_term(p: Parser<'_>) -> Result<'_, Self> {
+        if let Ok((_, p)) = p.clone().sigil('$') {
+            let (name, p) = p.identifier()?;
+
+            // Arguments of user-defined commands are known by the time they're parsed.
+            if let (Some(scope), true) = (p.scope(), name.bytes().all(|b| b.is_ascii_digit())) {
+                let arg = scope.get(name)?;
+                let v = Parser::new(&arg)
+                    .parse::<Value>()
+                    .and_then(|(v, p)| p.finish().map(|_| v))
+                    .unwrap_or_else(|_| Value::Str(arg.to_string()));
+
+                return Ok((Expr::Value(v), p));
+            }
+            Ok((Expr::Var(name.to_string()), p))
+        } else if let Ok((_, p)) = p.clone().sigil('(') {
+            let (_, p) = p.whitespace()?;
+            let (e, p) = p.parse::<Expr>()?;
+            let (_, p) = p.whitespace()?;
+            let (_, p) = p.sigil(')')?;
+            Ok((e, p))
+        } else {
+            let (v, p) = p.parse::<Value>()?;
+            Ok((Expr::Value(v), p))
+        }
+    }
+
+    /// Parse a binary expression whose operators have at least the given precedence.
+    fn parse_binary(p: Parser<'_>, precedence: u8) -> Result<'_, Self> {
+        let (mut lhs, mut p) = Self::parse_term(p)?;
+
+        loop {
+            let (_, q) = p.clone().whitespace()?;
+            let (op, q) = match q.parse::<BinOp>() {
+                Ok((op, q)) if op.precedence() >= precedence => (op, q),
+                _ => break,
+            };
+            let (_, q) = q.whitespace()?;
+            let (rhs, q) = Self::parse_binary(q, op.precedence() + 1)?;
+
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(rhs));
+            p = q;
+        }
+        Ok((lhs, p))
+    }
+}
+
+impl<'a> Parse<'a> for Expr {
+    fn parse(p: Parser<'a>) -> Result<'a, Self> {
+        Self::parse_binary(p, 0)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Value(v) => v.fmt(f),
+            Self::Var(name) => write!(f, "${}", name),
+            Self::Binary(lhs, op, rhs) => {
+                for (i, e) in [lhs, rhs].iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " {} ", op)?;
+                    }
+                    if let Self::Binary(..) = ***e {
+                        write!(f, "({})", e)?;
+                    } else {
+                        write!(f, "{}", e)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// The kind of word being completed on the command line.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Completion {
+    /// A command name, eg. `brush/set`.
+    Command,
+    /// A setting name, eg. `grid/spacing`.
+    Setting,
+    /// A brush mode, eg. `xsym`.
+    BrushMode,
+    /// A tool name, eg. `sampler`.
+    Tool,
+    /// A file path.
+    Path,
+}
+
+impl Completion {
+    /// Built-in command names, used for completion.
+    pub const COMMANDS: &'static [&'static str] = &[
+        "brush",
+        "brush/set",
+        "brush/size",
+        "brush/toggle",
+        "brush/unset",
+        "def",
+        "e",
+        "echo",
+        "else",
+        "end",
+        "f/add",
+        "f/clone",
+        "f/new",
+        "f/remove",
+        "f/resize",
+        "gradient",
+        "help",
+        "history",
+        "if",
+        "let",
+        "map",
+        "map/clear!",
+        "map/normal",
+        "map/visual",
+        "mode",
+        "outline",
+        "p/add",
+        "p/clear",
+        "p/sample",
+        "pan",
+        "q",
+        "q!",
+        "qa",
+        "qa!",
+        "redo",
+        "registers",
+        "repeat",
+        "reset!",
+        "sampler",
+        "sampler/off",
+        "selection/cell",
+        "selection/cut",
+        "selection/erase",
+        "selection/expand",
+        "selection/fill",
+        "selection/flip",
+        "selection/jump",
+        "selection/move",
+        "selection/new",
+        "selection/offset",
+        "selection/paste",
+        "selection/resize",
+        "selection/rotate",
+        "selection/scale",
+        "selection/shift",
+        "selection/write",
+        "selection/yank",
+        "set",
+        "slice",
+        "source",
+        "swap",
+        "toggle",
+        "tool",
+        "tool/prev",
+        "undo",
+        "unset",
+        "v/center",
+        "v/clear",
+        "v/next",
+        "v/prev",
+        "v/scale",
+        "visual",
+        "w",
+        "zoom",
+    ];
+
+    /// Tool names, used for completion.
+    pub const TOOLS: &'static [&'static str] = &["brush", "gradient", "pan", "sampler"];
+}
+
+/// Return the paths starting with the given prefix. Directories end with a `/`.
+pub fn complete_path(prefix: &str) -> Vec<String> {
+    let (dir, file) = match prefix.rfind('/') {
+        Some(i) => prefix.split_at(i + 1),
+        None => ("", prefix),
+    };
+    let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut paths = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        // Hidden files are only completed if asked for explicitly.
+        if !name.starts_with(file) || (name.starts_with('.') && !file.starts_with('.')) {
+            continue;
+        }
+        let mut path = format!("{}{}", dir, name);
+        if entry.path().is_dir() {
+            path.push('/');
+        }
+        paths.push(path);
+    }
+    paths
+}
+
 pub struct CommandLine {
     input: String,
+    /// Byte offset of the caret in the input.
+    cursor: usize,
+    /// Previously entered commands, oldest first.
+    history: Vec<String>,
+    /// The history entry currently shown, if any.
+    entry: Option<usize>,
+    /// The input that history entries are filtered by.
+    prefix: String,
+    /// Completion candidates being cycled through.
+    candidates: Vec<String>,
+    /// The candidate currently inserted, if any.
+    candidate: Option<usize>,
+    /// Byte offset of the word being completed.
+    word: usize,
 }
 
 impl CommandLine {
-    const MAX_INPUT: usize = 256;
+    /// Maximum number of history entries kept.
+    const MAX_HISTORY: usize = 1024;
 
     pub fn new() -> Self {
         Self {
-            input: String::with_capacity(Self::MAX_INPUT),
+            input: String::new(),
+            cursor: 0,
+            history: Vec::new(),
+            entry: None,
+            prefix: String::new(),
+            candidates: Vec::new(),
+            candidate: None,
+            word: 0,
         }
     }
 
@@ -475,24 +1197,350 @@ impl CommandLine {
         self.input.is_empty()
     }
 
-    pub fn putc(&mut self, c: char) {
-        if self.input.len() + 1 >= self.input.capacity() {
-            return;
-        }
+    /// Byte offset of the caret in the input.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
 
-        self.input.push(c);
+    /// Insert a character at the caret.
+    pub fn putc(&mut self, c: char) {
+        self.edited();
+        self.input.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
     }
 
+    /// Insert a string at the caret.
     pub fn puts(&mut self, s: &str) {
-        self.input.push_str(s);
+        self.edited();
+        self.input.insert_str(self.cursor, s);
+        self.cursor += s.len();
     }
 
+    /// Delete the character before the caret. The leading `:` is only deleted
+    /// once there is nothing else left.
     pub fn delc(&mut self) {
-        self.input.pop();
+        self.edited();
+        if self.input == ":" {
+            self.clear();
+        } else if self.cursor > self.start() {
+            let c = self.prev();
+            self.input.drain(c..self.cursor);
+            self.cursor = c;
+        }
+    }
+
+    /// Delete the character under the caret.
+    pub fn delete(&mut self) {
+        self.edited();
+        if self.cursor < self.input.len() {
+            let c = self.next();
+            self.input.drain(self.cursor..c);
+        }
+    }
+
+    /// Delete the word before the caret.
+    pub fn delete_word(&mut self) {
+        self.edited();
+        let before = &self.input[self.start()..self.cursor];
+        let word = before.trim_end_matches(char::is_whitespace);
+        let word = word.trim_end_matches(|c: char| !c.is_whitespace());
+        let start = self.start() + word.len();
+
+        self.input.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    /// Delete everything before the caret.
+    pub fn delete_to_start(&mut self) {
+        self.edited();
+        let start = self.start();
+
+        self.input.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    pub fn cursor_left(&mut self) {
+        if self.cursor > self.start() {
+            self.cursor = self.prev();
+        }
+    }
+
+    pub fn cursor_right(&mut self) {
+        if self.cursor < self.input.len() {
+            self.cursor = self.next();
+        }
+    }
+
+    pub fn cursor_home(&mut self) {
+        self.cursor = self.start();
+    }
+
+    pub fn cursor_end(&mut self) {
+        self.cursor = self.input.len();
     }
 
     pub fn clear(&mut self) {
+        self.edited();
         self.input.clear();
+        self.cursor = 0;
+    }
+
+    /// Offset of the first editable character, ie. after the leading `:`.
+    fn start(&self) -> usize {
+        if self.input.starts_with(':') {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Offset of the character before the caret.
+    fn prev(&self) -> usize {
+        self.input[..self.cursor]
+            .char_indices()
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    /// Offset of the character after the caret.
+    fn next(&self) -> usize {
+        self.input[self.cursor..]
+            .chars()
+            .next()
+            .map_or(self.cursor, |c| self.cursor + c.len_utf8())
+    }
+
+    /// Reset history navigation and completion after an edit.
+    fn edited(&mut self) {
+        self.candidates.clear();
+        self.entry = None;
+    }
+
+    /// Previously entered commands, oldest first.
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Add a command to the history. If it was already there, it is moved to the end.
+    /// Returns `false` if the command was empty, and wasn't added.
+    pub fn record(&mut self, input: &str) -> bool {
+        let input = input.trim_end();
+        if input.trim_start_matches(':').trim().is_empty() {
+            return false;
+        }
+        self.history.retain(|h| h != input);
+        self.history.push(input.to_owned());
+
+        if self.history.len() > Self::MAX_HISTORY {
+            self.history.drain(..self.history.len() - Self::MAX_HISTORY);
+        }
+        self.entry = None;
+
+        true
+    }
+
+    /// Show the previous history entry starting with what was typed.
+    pub fn history_prev(&mut self) {
+        if self.entry.is_none() {
+            self.prefix = self.input.clone();
+        }
+        let end = self.entry.unwrap_or(self.history.len());
+
+        if let Some(i) = self.history[..end]
+            .iter()
+            .rposition(|h| h.starts_with(&self.prefix))
+        {
+            self.show(Some(i));
+        }
+    }
+
+    /// Show the next history entry starting with what was typed, or what was
+    /// typed, if there is none.
+    pub fn history_next(&mut self) {
+        let start = match self.entry {
+            Some(i) => i + 1,
+            None => return,
+        };
+        let next = self.history[start..]
+            .iter()
+            .position(|h| h.starts_with(&self.prefix))
+            .map(|i| start + i);
+
+        self.show(next);
+    }
+
+    /// Load the history from a file, one command per line. Since commands are
+    /// appended to the file as they are entered, the file is rewritten without
+    /// duplicates if it has grown past the history.
+    pub fn load_history<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let history = match fs::read_to_string(path) {
+            Ok(history) => history,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut lines = 0;
+        for line in history.lines() {
+            self.record(line);
+            lines += 1;
+        }
+        if lines > self.history.len() {
+            self.save_history(path)?;
+        }
+        Ok(())
+    }
+
+    /// Append a command to the history file.
+    pub fn append_history<P: AsRef<Path>>(&self, path: P, input: &str) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        writeln!(file, "{}", input.trim_end())
+    }
+
+    /// Save the history to a file, one command per line.
+    pub fn save_history<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut history = self.history.join("\n");
+        history.push('\n');
+
+        fs::write(path, history)
+    }
+
+    /// Show the given history entry, or the typed input if `None`.
+    fn show(&mut self, entry: Option<usize>) {
+        self.input.clear();
+        self.input.push_str(match entry {
+            Some(i) => &self.history[i],
+            None => &self.prefix,
+        });
+        self.cursor = self.input.len();
+        self.candidates.clear();
+        self.entry = entry;
+    }
+
+    /// Return what kind of word is being completed, and the word so far.
+    pub fn completion(&self) -> Option<(Completion, &str)> {
+        self.context()
+            .map(|(c, start)| (c, &self.input[start..self.cursor]))
+    }
+
+    /// Complete the word being typed. Candidates for a given kind of word are
+    /// returned by `lookup`. If the word is ambiguous, it is first extended to
+    /// the longest common prefix, and further calls cycle through the candidates,
+    /// backwards if `reverse` is set. Returns the candidates if there is more than one.
+    pub fn complete<F>(&mut self, reverse: bool, lookup: F) -> &[String]
+    where
+        F: FnOnce(Completion, &str) -> Vec<String>,
+    {
+        if !self.candidates.is_empty() {
+            self.cycle(reverse);
+            return &self.candidates;
+        }
+        let (completion, start) = match self.context() {
+            Some(ctx) => ctx,
+            None => return &[],
+        };
+        let word = &self.input[start..self.cursor];
+        let mut candidates = lookup(completion, word);
+
+        candidates.retain(|c| c.starts_with(word));
+        candidates.sort();
+        candidates.dedup();
+
+        match candidates.as_slice() {
+            [] => &[],
+            [c] => {
+                let mut c = c.clone();
+                if completion != Completion::Path || !c.ends_with('/') {
+                    c.push(' ');
+                }
+                self.replace(start, &c);
+
+                &[]
+            }
+            [first, rest @ ..] => {
+                let prefix = rest.iter().fold(first.as_str(), |prefix, c| {
+                    let n = prefix
+                        .char_indices()
+                        .zip(c.chars())
+                        .find(|((_, a), b)| a != b)
+                        .map_or(prefix.len(), |((i, _), _)| i);
+                    &prefix[..n]
+                });
+                let extend = prefix.len() > word.len();
+                let prefix = prefix.to_owned();
+
+                self.word = start;
+                self.candidates = candidates;
+                self.candidate = None;
+
+                if extend {
+                    self.replace(start, &prefix);
+                } else {
+                    self.cycle(reverse);
+                }
+                &self.candidates
+            }
+        }
+    }
+
+    /// Insert the next completion candidate.
+    fn cycle(&mut self, reverse: bool) {
+        let n = self.candidates.len();
+        let i = match self.candidate {
+            Some(i) if reverse => (i + n - 1) % n,
+            Some(i) => (i + 1) % n,
+            None if reverse => n - 1,
+            None => 0,
+        };
+        self.candidate = Some(i);
+
+        let candidate = std::mem::take(&mut self.candidates[i]);
+        self.replace(self.word, &candidate);
+        self.candidates[i] = candidate;
+    }
+
+    /// Replace the input from `start` up to the caret with `s`.
+    fn replace(&mut self, start: usize, s: &str) {
+        self.input.replace_range(start..self.cursor, s);
+        self.cursor = start + s.len();
+    }
+
+    /// Return the kind of word being completed, and the offset at which it starts.
+    fn context(&self) -> Option<(Completion, usize)> {
+        let input = &self.input[..self.cursor];
+        let segment = input.rfind(SEPARATOR).map_or(0, |i| i + 1);
+        let rest = input[segment..].trim_start().trim_start_matches(':');
+        let start = input.len() - rest.len();
+
+        // Without any whitespace after it, it's the command name that is being completed.
+        let word = match rest.rfind(|c: char| c.is_ascii_whitespace()) {
+            Some(i) => start + i + 1,
+            None => return Some((Completion::Command, start)),
+        };
+        let mut args = input[start..word].split_whitespace();
+        let cmd = args.next()?;
+        let nargs = args.count();
+
+        let completion = match cmd {
+            "set" | "unset" | "toggle" if nargs == 0 => Completion::Setting,
+            "brush/set" | "brush/unset" | "brush/toggle" if nargs == 0 => Completion::BrushMode,
+            "tool" if nargs == 0 => Completion::Tool,
+            "e" => Completion::Path,
+            "w" | "source" | "selection/write" if nargs == 0 => Completion::Path,
+            _ => return None,
+        };
+        Some((completion, word))
     }
 }
 
@@ -502,7 +1550,31 @@ impl FromStr for Command {
     type Err = Error;
 
     fn from_str(input: &str) -> result::Result<Self, Self::Err> {
-        let p = Parser::new(input);
+        Command::parse_all(Parser::new(input))
+    }
+}
+
+impl Command {
+    /// Parse a command in the given scope, which `$` references and user-defined
+    /// commands are looked up in.
+    pub fn parse_in(input: &str, scope: &Scope) -> result::Result<Self, Error> {
+        Command::parse_all(Parser::with_scope(input, scope))
+    }
+
+    /// Check whether a name is that of a built-in command. User-defined commands
+    /// are only parsed as such when no built-in command has the same name, so
+    /// the parser is asked how it would parse a command of that name.
+    pub fn is_builtin(name: &str) -> bool {
+        let mut scope = Scope::default();
+        scope.commands.insert(name.to_owned());
+
+        !matches!(
+            Command::parse_in(&format!(":{}", name), &scope),
+            Ok(Command::Call(..))
+        )
+    }
+
+    fn parse_all(p: Parser<'_>) -> result::Result<Self, Error> {
         match p.parse::<Command>() {
             Ok((cmd, p)) => {
                 let (_, p) = p.clone().comment().unwrap_or(("", p));
@@ -515,12 +1587,49 @@ impl FromStr for Command {
     }
 }
 
+/// Parse an optional register name, which is a single letter or digit.
+fn register(p: Parser<'_>) -> Result<'_, Option<char>> {
+    if p.is_end() {
+        return Ok((None, p));
+    }
+    let (r, p) = p.parse::<char>()?;
+
+    if r.is_ascii_alphanumeric() {
+        Ok((Some(r), p))
+    } else {
+        Err(Error::new(format!("invalid register `{}`", r)))
+    }
+}
+
 impl<'a> Parse<'a> for Command {
     fn parse(p: Parser<'a>) -> Result<'a, Self> {
         let (_, p) = p.sigil(':')?;
+        let (cmd, mut p) = Command::parse_single(p)?;
+        let mut cmds = vec![cmd];
+
+        // Commands can be chained, eg. `:brush/set erase | brush/size 1`.
+        while let Ok((_, q)) = p.clone().whitespace().and_then(|(_, q)| q.sigil(SEPARATOR)) {
+            let (_, q) = q.whitespace()?;
+            let q = q.clone().sigil(':').map_or(q, |(_, q)| q);
+            let (cmd, q) = Command::parse_single(q)?;
+
+            cmds.push(cmd);
+            p = q;
+        }
+        if cmds.len() == 1 {
+            Ok((cmds.remove(0), p))
+        } else {
+            Ok((Command::Seq(cmds), p))
+        }
+    }
+}
+
+impl Command {
+    /// Parse a single command, without the leading `:`.
+    fn parse_single(p: Parser<'_>) -> Result<'_, Self> {
         let (_, p) = p.whitespace()?;
 
-        if p.is_empty() {
+        if p.is_end() {
             return Ok((Command::Noop, p));
         }
 
@@ -532,13 +1641,13 @@ impl<'a> Parse<'a> for Command {
         let (cmd, p) = p.identifier()?;
         let (_, p) = p.whitespace()?;
 
-        match cmd {
+        let (command, p) = match cmd {
             "q" => Ok((Command::Quit, p)),
             "qa" => Ok((Command::QuitAll, p)),
             "q!" => Ok((Command::ForceQuit, p)),
             "qa!" => Ok((Command::ForceQuitAll, p)),
             "w" => {
-                if p.is_empty() {
+                if p.is_end() {
                     Ok((Command::Write(None), p))
                 } else {
                     let (path, p) = p.path()?;
@@ -546,7 +1655,7 @@ impl<'a> Parse<'a> for Command {
                 }
             }
             "e" => {
-                if p.is_empty() {
+                if p.is_end() {
                     Ok((Command::Edit(Vec::with_capacity(0)), p))
                 } else {
                     let mut q = p;
@@ -565,29 +1674,49 @@ impl<'a> Parse<'a> for Command {
                 let (k, p) = p.identifier()?;
                 let (_, p) = p.whitespace()?;
 
-                if p.is_empty() {
-                    Ok((Command::Set(k.to_string(), Value::Bool(true)), p))
+                if p.is_end() {
+                    Ok((
+                        Command::Set(k.to_string(), Expr::Value(Value::Bool(true))),
+                        p,
+                    ))
                 } else {
                     let (_, p) = p.sigil('=')?;
                     let (_, p) = p.whitespace()?;
-                    let (v, p) = p.parse::<Value>()?;
-                    Ok((Command::Set(k.to_string(), v), p))
+                    let (e, p) = p.parse::<Expr>()?;
+                    Ok((Command::Set(k.to_string(), e), p))
                 }
             }
             "unset" => {
                 let (k, p) = p.identifier()?;
-                Ok((Command::Set(k.to_string(), Value::Bool(false)), p))
+                Ok((
+                    Command::Set(k.to_string(), Expr::Value(Value::Bool(false))),
+                    p,
+                ))
+            }
+            "let" => {
+                let (name, p) = p.identifier()?;
+                let (_, p) = p.whitespace()?;
+                let (_, p) = p.sigil('=')?;
+                let (_, p) = p.whitespace()?;
+                let (e, p) = p.parse::<Expr>()?;
+                Ok((Command::Let(name.to_string(), e), p))
+            }
+            "if" => {
+                let (e, p) = p.parse::<Expr>()?;
+                Ok((Command::If(e), p))
             }
+            "else" => Ok((Command::Else, p)),
+            "end" => Ok((Command::End, p)),
             "toggle" => {
                 let (k, p) = p.identifier()?;
                 Ok((Command::Toggle(k.to_string()), p))
             }
             "echo" => {
-                let (v, p) = p.parse::<Value>()?;
-                Ok((Command::Echo(v), p))
+                let (e, p) = p.parse::<Expr>()?;
+                Ok((Command::Echo(e), p))
             }
             "slice" => {
-                if p.is_empty() {
+                if p.is_end() {
                     Ok((Command::Slice(None), p))
                 } else {
                     let (n, p) = p.parse::<u32>()?;
@@ -637,9 +1766,25 @@ impl<'a> Parse<'a> for Command {
             "visual" => Ok((Command::Mode(Mode::Visual(VisualState::default())), p)),
             "sampler" => Ok((Command::Tool(Tool::Sampler), p)),
             "sampler/off" => Ok((Command::ToolPrev, p)),
+            "gradient" if p.is_end() => {
+                Ok((Command::Tool(Tool::Gradient(GradientState::default())), p))
+            }
+            "gradient" => {
+                let (from, p) = p.parse::<(i32, i32)>()?;
+                let (_, p) = p.whitespace()?;
+                let (to, p) = p.parse::<(i32, i32)>()?;
+                Ok((Command::Gradient(from, to), p))
+            }
             "v/next" => Ok((Command::ViewNext, p)),
             "v/prev" => Ok((Command::ViewPrev, p)),
             "v/center" => Ok((Command::ViewCenter, p)),
+            "v/scale" => {
+                let ((x, y), p) = p
+                    .clone()
+                    .parse::<(f64, f64)>()
+                    .or_else(|_| p.parse::<f64>().map(|(n, p)| ((n, n), p)))?;
+                Ok((Command::ViewScale(x as f32, y as f32), p))
+            }
             "v/clear" => {
                 if let Ok((rgba, p)) = p.clone().parse::<Rgba8>() {
                     Ok((Command::Fill(rgba), p))
@@ -700,10 +1845,11 @@ impl<'a> Parse<'a> for Command {
             }
             "tool" => {
                 let (t, p) = p.word()?;
-                match t {
+                match t.as_ref() {
                     "pan" => Ok((Command::Tool(Tool::Pan(PanState::default())), p)),
                     "brush" => Ok((Command::Tool(Tool::Brush(Brush::default())), p)),
                     "sampler" => Ok((Command::Tool(Tool::Sampler), p)),
+                    "gradient" => Ok((Command::Tool(Tool::Gradient(GradientState::default())), p)),
                     _ => Err(Error::new(format!("unknown tool {:?}", t))),
                 }
             }
@@ -714,13 +1860,61 @@ impl<'a> Parse<'a> for Command {
                 let ((x, y), p) = p.parse::<(i32, i32)>()?;
                 Ok((Command::SelectionMove(x, y), p))
             }
+            "selection/shift" => {
+                let ((x, y), p) = p.parse::<(i32, i32)>()?;
+                Ok((Command::SelectionShift(x, y), p))
+            }
+            "selection/flip" => {
+                let (axis, p) = p.parse::<Axis>()?;
+                Ok((Command::SelectionFlip(axis), p))
+            }
+            "selection/rotate" => {
+                let (degrees, p) = p.parse::<f64>()?;
+                Ok((Command::SelectionRotate(degrees as f32), p))
+            }
+            "selection/scale" => {
+                // Either `<x> <y>`, or a single factor for both axes.
+                let ((x, y), p) = p
+                    .clone()
+                    .parse::<(f64, f64)>()
+                    .or_else(|_| p.parse::<f64>().map(|(n, p)| ((n, n), p)))?;
+                Ok((Command::SelectionScale(x as f32, y as f32), p))
+            }
+            "selection/cell" => {
+                let ((x, y), p) = p.parse::<(u32, u32)>()?;
+                Ok((Command::SelectionCell(x, y), p))
+            }
             "selection/resize" => {
                 let ((x, y), p) = p.parse::<(i32, i32)>()?;
                 Ok((Command::SelectionResize(x, y), p))
             }
-            "selection/yank" => Ok((Command::SelectionYank, p)),
-            "selection/cut" => Ok((Command::SelectionCut, p)),
-            "selection/paste" => Ok((Command::SelectionPaste, p)),
+            "selection/yank" => {
+                let (r, p) = register(p)?;
+                Ok((Command::SelectionYank(r), p))
+            }
+            "selection/cut" => {
+                let (r, p) = register(p)?;
+                Ok((Command::SelectionCut(r), p))
+            }
+            "selection/paste" => {
+                let (r, p) = register(p)?;
+                Ok((Command::SelectionPaste(r), p))
+            }
+            "registers" => Ok((Command::Registers, p)),
+            "repeat" => Ok((Command::Repeat, p)),
+            "history" => {
+                if p.is_end() {
+                    Ok((Command::History(8), p))
+                } else {
+                    let (n, p) = p.parse::<u32>()?;
+                    Ok((Command::History(n as usize), p))
+                }
+            }
+            "selection/write" => {
+                let (path, p) = p.path()?;
+                Ok((Command::SelectionWrite(path), p))
+            }
+            "selection/new" => Ok((Command::SelectionNew, p)),
             "selection/expand" => Ok((Command::SelectionExpand, p)),
             "selection/erase" => Ok((Command::SelectionErase, p)),
             "selection/offset" => {
@@ -731,6 +1925,13 @@ impl<'a> Parse<'a> for Command {
                 let (dir, p) = p.parse::<Direction>()?;
                 Ok((Command::SelectionJump(dir), p))
             }
+            "outline" => {
+                if let Ok((rgba, p)) = p.clone().parse::<Rgba8>() {
+                    Ok((Command::Outline(Some(rgba)), p))
+                } else {
+                    Ok((Command::Outline(None), p))
+                }
+            }
             "selection/fill" => {
                 if let Ok((rgba, p)) = p.clone().parse::<Rgba8>() {
                     Ok((Command::SelectionFill(Some(rgba)), p))
@@ -738,10 +1939,416 @@ impl<'a> Parse<'a> for Command {
                     Ok((Command::SelectionFill(None), p))
                 }
             }
+            "def" => {
+                if p.is_end() {
+                    return Ok((Command::DefList, p));
+                }
+                let (name, p) = p.identifier()?;
+                let (_, p) = p.whitespace()?;
+                let (body, p) = p.leftover()?;
+
+                if body.is_empty() {
+                    return Err(Error::new(format!("missing definition for ':{}'", name)));
+                }
+                Ok((Command::Def(name.to_string(), body.to_string()), p))
+            }
+            name if p.scope().is_some_and(|s| s.commands.contains(name)) => {
+                let mut args = Vec::new();
+                let mut p = p;
+
+                while !p.is_end() && p.peek() != Some('{') && p.peek() != Some('}') {
+                    // Stop at a trailing comment.
+                    if p.clone().comment().is_ok() {
+                        break;
+                    }
+                    let (arg, q) = if p.peek() == Some('"') {
+                        let (arg, q) = p.clone().string()?;
+                        (arg.to_owned(), q)
+                    } else {
+                        let (arg, q) = p.clone().word()?;
+                        (arg.into_owned(), q)
+                    };
+                    let (_, q) = q.whitespace()?;
+
+                    args.push(arg);
+                    p = q;
+                }
+                Ok((Command::Call(name.to_string(), args), p))
+            }
             unrecognized => Err(Error::new(format!(
                 "unrecognized command ':{}'",
                 unrecognized
             ))),
+        }?;
+        let (_, p) = p.whitespace()?;
+
+        // Repeatable commands may be followed by a count, eg. `:undo 5`.
+        if command.repeats() && !p.is_end() {
+            if let Ok((n, p)) = p.clone().parse::<u32>() {
+                return Ok((
+                    Command::Count(n.min(Command::MAX_COUNT), Box::new(command)),
+                    p,
+                ));
+            }
+        }
+        Ok((command, p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_unrecognized_command() {
+        assert!(Command::from_str(":undoo").is_err());
+        assert!(Command::parse_in(":export-all", &Scope::default()).is_err());
+    }
+
+    #[test]
+    fn test_call() {
+        let mut scope = Scope::default();
+        scope.commands.insert(String::from("export-all"));
+
+        match Command::parse_in(r#":export-all "a | b" 2 -- comment"#, &scope) {
+            Ok(Command::Call(name, args)) => {
+                assert_eq!(name, "export-all");
+                assert_eq!(args, vec!["a | b", "2"]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_is_builtin() {
+        assert!(Command::is_builtin("undo"));
+        assert!(Command::is_builtin("selection/yank"));
+        // Built-in commands which require arguments.
+        assert!(Command::is_builtin("brush/size"));
+        assert!(Command::is_builtin("def"));
+        assert!(!Command::is_builtin("export-all"));
+        assert!(!Command::is_builtin("undoo"));
+    }
+
+    #[test]
+    fn test_gradient() {
+        assert!(matches!(
+            Command::from_str(":gradient"),
+            Ok(Command::Tool(Tool::Gradient(_)))
+        ));
+        assert!(matches!(
+            Command::from_str(":gradient 0 0 8 -8"),
+            Ok(Command::Gradient((0, 0), (8, -8)))
+        ));
+        assert!(Command::from_str(":gradient 0 0").is_err());
+    }
+
+    #[test]
+    fn test_call_arguments() {
+        let mut scope = Scope::default();
+        scope.args = vec![
+            String::from("8"),
+            String::from("1 | q"),
+            String::from("#ff0000"),
+        ];
+
+        assert!(matches!(
+            Command::parse_in(":f/resize $1 $1", &scope),
+            Ok(Command::ResizeFrame(8, 8))
+        ));
+        assert!(matches!(
+            Command::parse_in(":p/add $3", &scope),
+            Ok(Command::PaletteAdd(c)) if c == Rgba8::new(0xff, 0, 0, 0xff)
+        ));
+        assert!(matches!(
+            Command::parse_in(":let x = $1 + 1", &scope),
+            Ok(Command::Let(_, Expr::Binary(lhs, BinOp::Add, _)))
+                if *lhs == Expr::Value(Value::U32(8))
+        ));
+        // Arguments are substituted as a whole, and can't add commands.
+        assert!(Command::parse_in(":f/resize $1 $2", &scope).is_err());
+        assert!(Command::parse_in(":f/resize $1 $4", &scope).is_err());
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut vars = HashMap::new();
+        vars.insert(String::from("w"), String::from("16"));
+        vars.insert(String::from("fg"), String::from("#ff0000ff"));
+
+        let scope = Scope {
+            vars: Some(&vars),
+            ..Scope::default()
+        };
+
+        assert!(matches!(
+            Command::parse_in(":f/resize $w $w", &scope),
+            Ok(Command::ResizeFrame(16, 16))
+        ));
+        assert!(matches!(
+            Command::parse_in(":p/add $fg", &scope),
+            Ok(Command::PaletteAdd(c)) if c == Rgba8::new(0xff, 0, 0, 0xff)
+        ));
+        // Expressions look up variables when they're evaluated.
+        assert!(matches!(
+            Command::parse_in(":echo $h", &scope),
+            Ok(Command::Echo(Expr::Var(name))) if name == "h"
+        ));
+        assert!(Command::parse_in(":f/resize $w $h", &scope).is_err());
+        assert!(Command::parse_in(":p/add $w", &scope).is_err());
+    }
+
+    fn mapping(input: &str) -> result::Result<KeyMapping, Error> {
+        KeyMapping::parse(Parser::new(input), &[Mode::Normal]).map(|(km, _)| km)
+    }
+
+    #[test]
+    fn test_key_mapping_modifiers() {
+        let km = mapping("<ctrl><shift>z :redo").unwrap();
+
+        assert_eq!(km.key, Key::Virtual(platform::Key::Z));
+        assert_eq!(
+            km.modifiers,
+            platform::ModifiersState {
+                ctrl: true,
+                shift: true,
+                ..Default::default()
+            }
+        );
+        assert!(km.sequence.is_empty());
+        assert!(matches!(km.press, Command::Redo));
+        assert_eq!(km.display(), "<ctrl><shift>z");
+
+        // A modifier key on its own is a key.
+        let km = mapping("<shift> :brush/set erase {:brush/unset erase}").unwrap();
+
+        assert_eq!(km.key, Key::Virtual(platform::Key::Shift));
+        assert_eq!(km.modifiers, platform::ModifiersState::default());
+        assert!(km.release.is_some());
+    }
+
+    #[test]
+    fn test_key_mapping_sequence() {
+        let km = mapping("g g :v/center").unwrap();
+
+        assert_eq!(km.key, Key::Virtual(platform::Key::G));
+        assert_eq!(
+            km.sequence,
+            vec![(
+                platform::ModifiersState::default(),
+                Key::Virtual(platform::Key::G)
+            )]
+        );
+        assert!(matches!(km.press, Command::ViewCenter));
+        assert_eq!(km.display(), "g g");
+
+        let km = mapping("<ctrl>x <ctrl>s :w").unwrap();
+        assert_eq!(km.sequence.len(), 1);
+        assert_eq!(km.display(), "<ctrl>x <ctrl>s");
+    }
+
+    #[test]
+    fn test_key_mapping_sequence_release() {
+        assert!(mapping("z :zoom + {:zoom -}").is_ok());
+        assert!(mapping("g z :zoom + {:zoom -}").is_err());
+    }
+
+    #[test]
+    fn test_key_mapping_deferred() {
+        let mut vars = HashMap::new();
+        vars.insert(String::from("fg"), String::from("#ff0000ff"));
+
+        let scope = Scope {
+            vars: Some(&vars),
+            ..Scope::default()
+        };
+        let (km, _) = KeyMapping::parse(
+            Parser::with_scope("x :p/add $fg {:p/clear}", &scope),
+            &[Mode::Normal],
+        )
+        .unwrap();
+
+        // References are only substituted when the mapping is run.
+        assert!(matches!(
+            km.press,
+            Command::Deferred(ref body, _) if body == ":p/add $fg"
+        ));
+        assert!(matches!(km.release, Some(Command::PaletteClear)));
+
+        // Mappings are still checked when they're defined.
+        assert!(
+            KeyMapping::parse(Parser::with_scope("x :p/add $bg", &scope), &[Mode::Normal]).is_err()
+        );
+    }
+
+    fn cmdline(input: &str) -> CommandLine {
+        let mut c = CommandLine::new();
+        c.puts(input);
+        c
+    }
+
+    #[test]
+    fn test_cmdline_insert() {
+        let mut c = cmdline(":zoom 2");
+
+        c.cursor_left();
+        c.putc('1');
+        assert_eq!(c.input(), ":zoom 12");
+        assert_eq!(c.cursor(), 7);
+
+        c.cursor_home();
+        c.puts("v/");
+        assert_eq!(c.input(), ":v/zoom 12");
+        assert_eq!(c.cursor(), 3);
+
+        // The leading `:` can't be deleted while there's more input.
+        c.cursor_home();
+        c.delc();
+        assert_eq!(c.input(), ":v/zoom 12");
+
+        let mut c = cmdline(":echo é");
+        c.cursor_left();
+        c.putc('x');
+        assert_eq!(c.input(), ":echo xé");
+        c.cursor_right();
+        assert_eq!(c.cursor(), c.input().len());
+        c.delc();
+        assert_eq!(c.input(), ":echo x");
+    }
+
+    #[test]
+    fn test_cmdline_delete_word() {
+        // Words are delimited by whitespace only.
+        let mut c = cmdline(":f/resize 16 16");
+        c.delete_word();
+        assert_eq!(c.input(), ":f/resize 16 ");
+        c.delete_word();
+        assert_eq!(c.input(), ":f/resize ");
+        c.delete_word();
+        assert_eq!(c.input(), ":");
+        c.delete_word();
+        assert_eq!(c.input(), ":");
+
+        let mut c = cmdline(":echo \"héllo wörld\"");
+        c.delete_word();
+        assert_eq!(c.input(), ":echo \"héllo ");
+        assert_eq!(c.cursor(), c.input().len());
+
+        let mut c = cmdline(":set scale 2");
+        c.cursor_left();
+        c.cursor_left();
+        c.delete_word();
+        assert_eq!(c.input(), ":set  2");
+        assert_eq!(c.cursor(), 5);
+    }
+
+    #[test]
+    fn test_cmdline_history() {
+        let mut c = CommandLine::new();
+
+        for input in &[":zoom 2", ":set grid on", ":zoom 4", ":zoom 2", ":"] {
+            c.record(input);
         }
+        assert_eq!(c.history(), &[":set grid on", ":zoom 4", ":zoom 2"]);
+
+        // Only entries starting with what was typed are shown.
+        c.puts(":zo");
+        c.history_prev();
+        assert_eq!(c.input(), ":zoom 2");
+        c.history_prev();
+        assert_eq!(c.input(), ":zoom 4");
+        c.history_prev();
+        assert_eq!(c.input(), ":zoom 4");
+        assert_eq!(c.cursor(), c.input().len());
+
+        c.history_next();
+        assert_eq!(c.input(), ":zoom 2");
+        c.history_next();
+        assert_eq!(c.input(), ":zo");
+        c.history_next();
+        assert_eq!(c.input(), ":zo");
+
+        // Editing an entry starts a new search, filtered by the edited input.
+        c.clear();
+        c.history_prev();
+        c.history_prev();
+        c.history_prev();
+        assert_eq!(c.input(), ":set grid on");
+        c.delc();
+        c.delc();
+        c.history_prev();
+        assert_eq!(c.input(), ":set grid on");
+        c.history_next();
+        assert_eq!(c.input(), ":set grid ");
+    }
+
+    #[test]
+    fn test_cmdline_history_file() {
+        let path = std::env::temp_dir().join(format!("rx-history-{}", std::process::id()));
+        let mut c = CommandLine::new();
+
+        for input in &[":zoom 2", ":zoom 4", ":zoom 2"] {
+            assert!(c.record(input));
+            c.append_history(&path, input).unwrap();
+        }
+        assert!(!c.record(":"));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        // Loading compacts the file, keeping the latest entry of each command.
+        let mut c = CommandLine::new();
+        c.load_history(&path).unwrap();
+        assert_eq!(c.history(), &[":zoom 4", ":zoom 2"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), ":zoom 4\n:zoom 2\n");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cmdline_complete() {
+        let commands = |completion, _: &str| {
+            assert_eq!(completion, Completion::Command);
+            vec![
+                String::from("tool"),
+                String::from("toggle"),
+                String::from("tool/prev"),
+                String::from("zoom"),
+            ]
+        };
+        let mut c = cmdline(":zoom 2 | to");
+
+        assert_eq!(c.completion(), Some((Completion::Command, "to")));
+        assert_eq!(
+            c.complete(false, commands),
+            &["toggle", "tool", "tool/prev"]
+        );
+        assert_eq!(c.input(), ":zoom 2 | toggle");
+        c.complete(false, commands);
+        assert_eq!(c.input(), ":zoom 2 | tool");
+        c.complete(true, commands);
+        c.complete(true, commands);
+        assert_eq!(c.input(), ":zoom 2 | tool/prev");
+
+        // Ambiguous words are first extended to the common prefix.
+        let mut c = cmdline(":set gr");
+        let settings = |_, _: &str| vec![String::from("grid"), String::from("grid/spacing")];
+        assert_eq!(c.completion(), Some((Completion::Setting, "gr")));
+        assert_eq!(c.complete(false, settings).len(), 2);
+        assert_eq!(c.input(), ":set grid");
+
+        let mut c = cmdline(":tool sa");
+        assert!(c
+            .complete(false, |_, _| vec![String::from("sampler")])
+            .is_empty());
+        assert_eq!(c.input(), ":tool sampler ");
+
+        // Directories can be completed further, so no space is added.
+        let mut c = cmdline(":e sr");
+        c.complete(false, |_, _| vec![String::from("src/")]);
+        assert_eq!(c.input(), ":e src/");
+
+        let mut c = cmdline(":zoom 2");
+        assert_eq!(c.completion(), None);
+        assert!(c.complete(false, |_, _| unreachable!()).is_empty());
     }
 }
@@ -1,5 +1,8 @@
 use rgx::kit::Rgba8;
 
+use std::fmt;
+use std::str::FromStr;
+
 pub const WHITE: Rgba8 = Rgba8::new(0xff, 0xff, 0xff, 0xff);
 pub const BLACK: Rgba8 = Rgba8::new(0x00, 0x00, 0x00, 0xff);
 pub const GREY: Rgba8 = Rgba8::new(0x88, 0x88, 0x88, 0xff);
@@ -10,3 +13,77 @@ pub const YELLOW: Rgba8 = Rgba8::new(0xff, 0xff, 0x66, 0xff);
 pub const LIGHT_GREEN: Rgba8 = Rgba8::new(0xbb, 0xff, 0xee, 0xff);
 pub const GREEN: Rgba8 = Rgba8::new(0x38, 0xb7, 0x55, 0xff);
 pub const BLUE: Rgba8 = Rgba8::new(0x29, 0x36, 0x6f, 0xff);
+
+/// A blend mode, used when compositing a color onto another.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Blend {
+    /// Standard "source-over" alpha compositing.
+    Over,
+    /// Replace the destination color with the source color.
+    Replace,
+    /// Multiply the source and destination colors.
+    Multiply,
+    /// Add the source and destination colors.
+    Add,
+}
+
+impl Blend {
+    /// Composite `src` onto `dst` using this blend mode. Colors are
+    /// treated as non-premultiplied.
+    pub fn apply(self, src: Rgba8, dst: Rgba8) -> Rgba8 {
+        if self == Blend::Replace {
+            return src;
+        }
+        let sa = src.a as f32 / 255.;
+        let da = dst.a as f32 / 255.;
+        let oa = sa + da * (1. - sa);
+
+        if oa <= 0. {
+            return Rgba8::TRANSPARENT;
+        }
+
+        let channel = |s: u8, d: u8| {
+            let (s, d) = (s as f32 / 255., d as f32 / 255.);
+            let b = match self {
+                Blend::Over | Blend::Replace => s,
+                Blend::Multiply => s * d,
+                Blend::Add => f32::min(s + d, 1.),
+            };
+            let c = sa * (1. - da) * s + sa * da * b + (1. - sa) * da * d;
+
+            (c / oa * 255.).round() as u8
+        };
+
+        Rgba8::new(
+            channel(src.r, dst.r),
+            channel(src.g, dst.g),
+            channel(src.b, dst.b),
+            (oa * 255.).round() as u8,
+        )
+    }
+}
+
+impl fmt::Display for Blend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Blend::Over => "over".fmt(f),
+            Blend::Replace => "replace".fmt(f),
+            Blend::Multiply => "multiply".fmt(f),
+            Blend::Add => "add".fmt(f),
+        }
+    }
+}
+
+impl FromStr for Blend {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "over" | "normal" => Ok(Blend::Over),
+            "replace" => Ok(Blend::Replace),
+            "multiply" => Ok(Blend::Multiply),
+            "add" => Ok(Blend::Add),
+            other => Err(format!("unknown blend mode `{}`", other)),
+        }
+    }
+}
@@ -3,9 +3,10 @@ use crate::color;
 use crate::cursor2d;
 use crate::execution::Execution;
 use crate::font::TextBatch;
+use crate::gradient::GradientShape;
 use crate::platform;
 use crate::session;
-use crate::session::{Mode, Rgb8, Session, Tool, VisualState};
+use crate::session::{GradientState, Mode, Rgb8, Session, Tool, VisualState};
 use crate::view::{View, ViewCoords};
 
 use rgx::core::Rgba;
@@ -13,7 +14,7 @@ use rgx::kit::shape2d::{Fill, Line, Rotation, Shape, Stroke};
 use rgx::kit::{self, Geometry};
 use rgx::kit::{shape2d, sprite2d};
 use rgx::kit::{Origin, Rgba8, ZDepth};
-use rgx::math::{Matrix4, Vector2};
+use rgx::math::{Matrix4, Point2, Vector2};
 use rgx::rect::Rect;
 
 use std::cell::RefCell;
@@ -21,6 +22,7 @@ use std::rc::Rc;
 use std::time;
 
 pub const CHECKER_LAYER: ZDepth = ZDepth(-0.9);
+pub const TILE_LAYER: ZDepth = ZDepth(-0.8);
 pub const VIEW_LAYER: ZDepth = ZDepth(-0.7);
 pub const BRUSH_LAYER: ZDepth = ZDepth(-0.6);
 pub const GRID_LAYER: ZDepth = ZDepth(-0.5);
@@ -31,6 +33,8 @@ pub const HELP_LAYER: ZDepth = ZDepth(-0.1);
 pub const CURSOR_LAYER: ZDepth = ZDepth(0.0);
 pub const XRAY_RADIUS: f32 = 3.0;
 pub const XRAY_MIN_ZOOM: f32 = 16.0;
+/// Number of frames between each step of the selection's marching ants.
+pub const MARCHING_ANTS_FRAMES: u64 = 8;
 
 pub const GLYPH_WIDTH: f32 = 8.;
 pub const GLYPH_HEIGHT: f32 = 14.;
@@ -78,6 +82,7 @@ pub mod cursors {
         let cursor = match t {
             Tool::Sampler => self::SAMPLER,
             Tool::Pan(_) => self::PAN,
+            Tool::Gradient(_) => self::CROSSHAIR,
 
             Tool::Brush(b) => match m {
                 Mode::Visual(_) if in_selection && in_view => self::OMNI,
@@ -169,16 +174,56 @@ fn draw_ui(session: &Session, canvas: &mut shape2d::Batch, text: &mut TextBatch)
 
         let t = Matrix4::from_translation(offset.extend(0.)) * Matrix4::from_scale(view.zoom);
 
-        // Selection stroke.
-        canvas.add(Shape::Rectangle(
-            r.map(|n| n as f32).transform(t),
-            self::UI_LAYER,
-            Rotation::ZERO,
-            Stroke::new(1., stroke.into()),
-            Fill::Empty(),
-        ));
+        if let Some(mask) = session.selection_mask() {
+            // Selection stroke, drawn as "marching ants" along the mask outline.
+            let phase = (session.frame_number() / self::MARCHING_ANTS_FRAMES) as i32;
+
+            for (a, b) in mask.outline() {
+                let color = if (a.x + a.y + phase).rem_euclid(4) < 2 {
+                    color::WHITE
+                } else {
+                    color::BLACK
+                };
+                canvas.add(Shape::Line(
+                    Line::new(a.x as f32, a.y as f32, b.x as f32, b.y as f32).transform(t),
+                    self::UI_LAYER,
+                    Rotation::ZERO,
+                    Stroke::new(1., color.into()),
+                ));
+            }
+            // Selection fill, one shape per horizontal run of selected pixels.
+            let mut pixels = mask.pixels().filter(|p| view.bounds().contains(*p));
+
+            if let Some(first) = pixels.next() {
+                let mut run = Rect::new(first.x, first.y, first.x + 1, first.y + 1);
+
+                for p in pixels.chain(std::iter::once(Point2::new(i32::MIN, i32::MIN))) {
+                    if p.y == run.y1 && p.x == run.x2 {
+                        run.x2 += 1;
+                        continue;
+                    }
+                    canvas.add(Shape::Rectangle(
+                        run.map(|n| n as f32).transform(t),
+                        self::UI_LAYER,
+                        Rotation::ZERO,
+                        Stroke::NONE,
+                        Fill::Solid(fill.into()),
+                    ));
+                    run = Rect::new(p.x, p.y, p.x + 1, p.y + 1);
+                }
+            }
+        } else {
+            // Selection stroke.
+            canvas.add(Shape::Rectangle(
+                r.map(|n| n as f32).transform(t),
+                self::UI_LAYER,
+                Rotation::ZERO,
+                Stroke::new(1., stroke.into()),
+                Fill::Empty(),
+            ));
+        }
         // Selection fill.
-        if r.intersects(view.bounds()) {
+        if session.selection_mask().is_none() && r.intersects(view.bounds()) {
             canvas.add(Shape::Rectangle(
                 r.intersection(view.bounds()).map(|n| n as f32).transform(t),
                 self::UI_LAYER,
@@ -189,6 +234,27 @@ fn draw_ui(session: &Session, canvas: &mut shape2d::Batch, text: &mut TextBatch)
         }
     }
 
+    // Lasso selection in the making.
+    if let Some(draft) = &session.selection_draft {
+        let offset = session.offset + view.offset;
+        let t = Matrix4::from_translation(offset.extend(0.)) * Matrix4::from_scale(view.zoom);
+
+        for (a, b) in draft.lasso.iter().zip(draft.lasso.iter().skip(1)) {
+            canvas.add(Shape::Line(
+                Line::new(
+                    a.x as f32 + 0.5,
+                    a.y as f32 + 0.5,
+                    b.x as f32 + 0.5,
+                    b.y as f32 + 0.5,
+                )
+                .transform(t),
+                self::UI_LAYER,
+                Rotation::ZERO,
+                Stroke::new(1., color::RED.into()),
+            ));
+        }
+    }
+
     for (id, v) in session.views.iter() {
         let offset = v.offset + session.offset;
 
@@ -300,6 +366,27 @@ fn draw_ui(session: &Session, canvas: &mut shape2d::Batch, text: &mut TextBatch)
     if session.mode == Mode::Command {
         let s = format!("{}", &session.cmdline.input());
         text.add(&s, MARGIN, MARGIN, self::TEXT_LAYER, Rgba8::WHITE);
+
+        // Caret
+        let x = MARGIN + s[..session.cmdline.cursor()].chars().count() as f32 * self::GLYPH_WIDTH;
+        canvas.add(Shape::Line(
+            Line::new(x, MARGIN - 1., x, MARGIN + self::GLYPH_HEIGHT + 1.),
+            self::UI_LAYER,
+            Rotation::ZERO,
+            Stroke::new(1., Rgba::WHITE),
+        ));
+
+        // Completion candidates, if any, are shown above the status line.
+        let s = format!("{}", &session.message);
+        if !s.is_empty() {
+            text.add(
+                &s,
+                MARGIN,
+                MARGIN + self::LINE_HEIGHT * 2.,
+                self::TEXT_LAYER,
+                session.message.color(),
+            );
+        }
     } else if !session.message.is_replay()
         && !session.message.is_debug()
         && session.settings["ui/message"].is_set()
@@ -313,6 +400,18 @@ fn draw_ui(session: &Session, canvas: &mut shape2d::Batch, text: &mut TextBatch)
             session.message.color(),
         );
     }
+
+    // Count typed before a key binding.
+    if let Some(n) = session.count {
+        let s = n.to_string();
+        text.add(
+            &s,
+            session.width - MARGIN - s.len() as f32 * self::GLYPH_WIDTH,
+            MARGIN,
+            self::TEXT_LAYER,
+            Rgba8::WHITE,
+        );
+    }
 }
 
 fn draw_overlay(
@@ -513,6 +612,30 @@ fn draw_brush(session: &Session, shapes: &mut shape2d::Batch) {
     let c = session.cursor;
     let z = v.zoom;
 
+    // Draw the gradient direction while dragging.
+    if let Tool::Gradient(GradientState::Dragging(from)) = session.tool {
+        let from = *session.session_coords(v.id, ViewCoords::new(from.x as f32, from.y as f32))
+            + Vector2::new(z / 2., z / 2.);
+        let stroke = Stroke::new(1., color::GREY.into());
+
+        shapes.add(Shape::Line(
+            Line::new(from.x, from.y, c.x, c.y),
+            self::UI_LAYER,
+            Rotation::ZERO,
+            stroke,
+        ));
+        if session.settings["gradient/shape"].gradient_shape() == GradientShape::Radial {
+            shapes.add(Shape::Circle(
+                from,
+                self::UI_LAYER,
+                Vector2::new(c.x - from.x, c.y - from.y).magnitude(),
+                32,
+                stroke,
+                Fill::Empty(),
+            ));
+        }
+    }
+
     match session.mode {
         Mode::Visual(VisualState::Selecting { .. }) => {
             if session.is_selected(session.view_coords(v.id, c).into()) {
@@ -532,6 +655,19 @@ fn draw_brush(session: &Session, shapes: &mut shape2d::Batch) {
         }
         Mode::Normal => {
             if let Tool::Brush(ref brush) = session.tool {
+                // With the stroke stabilizer on, the brush is drawn where the paint
+                // actually lands, attached to the cursor by a "string".
+                let c = if let Some(s) = session.stabilized_cursor {
+                    shapes.add(Shape::Line(
+                        Line::new(s.x, s.y, c.x, c.y),
+                        self::UI_LAYER,
+                        Rotation::ZERO,
+                        Stroke::new(1., color::GREY.into()),
+                    ));
+                    s
+                } else {
+                    c
+                };
                 let view_coords = session.active_view_coords(c);
 
                 // Draw enabled brush
@@ -618,7 +754,9 @@ fn draw_brush(session: &Session, shapes: &mut shape2d::Batch) {
 }
 
 fn draw_paste(session: &Session, batch: &mut sprite2d::Batch) {
-    if let (Mode::Visual(VisualState::Pasting), Some(s)) = (session.mode, session.selection) {
+    let pasting = session.mode == Mode::Visual(VisualState::Pasting) || session.floating;
+
+    if let (true, Some(s)) = (pasting, session.selection) {
         batch.add(
             Rect::origin(batch.w as f32, batch.h as f32),
             Rect::new(s.x1 as f32, s.y1 as f32, s.x2 as f32 + 1., s.y2 as f32 + 1.),
@@ -643,6 +781,39 @@ pub fn draw_view_animation(session: &Session, v: &View) -> sprite2d::Batch {
     )
 }
 
+/// Draw the frame under the cursor repeated around itself, so that
+/// tiling seams are visible.
+pub fn draw_view_tiles(session: &Session, v: &View) -> sprite2d::Batch {
+    let mut batch = sprite2d::Batch::new(v.width(), v.height());
+    let (fw, fh) = (v.fw as f32, v.fh as f32);
+
+    let cursor = session.view_coords(v.id, session.cursor);
+    let frame = (cursor.x / fw)
+        .floor()
+        .max(0.)
+        .min(v.animation.len() as f32 - 1.);
+    let src = Rect::new(frame * fw, 0., (frame + 1.) * fw, fh);
+
+    for y in -1..=1 {
+        for x in -1..=1 {
+            if x == 0 && y == 0 {
+                continue;
+            }
+            let dst = src + Vector2::new(x as f32 * fw, y as f32 * fh);
+
+            batch.add(
+                src,
+                dst * v.zoom + (session.offset + v.offset),
+                self::TILE_LAYER,
+                Rgba::TRANSPARENT,
+                1.,
+                kit::Repeat::default(),
+            );
+        }
+    }
+    batch
+}
+
 pub fn draw_help(session: &Session, text: &mut TextBatch, shape: &mut shape2d::Batch) {
     shape.add(Shape::Rectangle(
         Rect::origin(session.width as f32, session.height as f32),
@@ -74,13 +74,17 @@ impl From<Event> for String {
             Event::CursorMoved(platform::LogicalPosition { x, y }) => {
                 format!("cursor/moved {} {}", x, y)
             }
-            Event::KeyboardInput(platform::KeyboardInput { key, state, .. }) => {
+            Event::KeyboardInput(platform::KeyboardInput {
+                key,
+                state,
+                modifiers,
+            }) => {
                 let state = match state {
                     platform::InputState::Pressed => "pressed",
                     platform::InputState::Released => "released",
                     platform::InputState::Repeated => "repeated",
                 };
-                format!("keyboard/input {} {}", key.unwrap(), state)
+                format!("keyboard/input {}{} {}", modifiers, key.unwrap(), state)
             }
             Event::ReceivedCharacter(c) => format!("char/received '{}'", c),
         }
@@ -95,7 +99,7 @@ impl FromStr for Event {
         let (event, p) = p.word()?;
         let (_, p) = p.whitespace()?;
 
-        let result: Result<(Self, parser::Parser), Self::Err> = match event {
+        let result: Result<(Self, parser::Parser), Self::Err> = match event.as_ref() {
             "mouse/input" => {
                 let (s, p) = p.parse::<platform::InputState>()?;
                 Ok((Event::MouseInput(platform::MouseButton::Left, s), p))
@@ -112,6 +116,7 @@ impl FromStr for Event {
                 ))
             }
             "keyboard/input" => {
+                let (m, p) = p.parse::<platform::ModifiersState>()?;
                 let (k, p) = p.parse::<cmd::Key>()?;
                 let (_, p) = p.whitespace()?;
                 let (s, p) = p.parse::<platform::InputState>()?;
@@ -120,7 +125,7 @@ impl FromStr for Event {
                     Event::KeyboardInput(platform::KeyboardInput {
                         state: s,
                         key: Some(k),
-                        modifiers: platform::ModifiersState::default(),
+                        modifiers: m,
                     }),
                     p,
                 ))
@@ -141,3 +146,51 @@ impl FromStr for Event {
         Ok(event)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyboard_input_round_trip() {
+        let shift = platform::ModifiersState {
+            shift: true,
+            ..Default::default()
+        };
+        let ctrl_meta = platform::ModifiersState {
+            ctrl: true,
+            meta: true,
+            ..Default::default()
+        };
+        let inputs = [
+            (platform::ModifiersState::default(), platform::Key::G),
+            (ctrl_meta, platform::Key::Z),
+            (ctrl_meta, platform::Key::F12),
+            (shift, platform::Key::KpAdd),
+            // Modifier keys are reported as held while they're pressed.
+            (shift, platform::Key::Shift),
+            (ctrl_meta, platform::Key::Minus),
+        ];
+
+        for (modifiers, key) in inputs.iter().cloned() {
+            for state in [
+                platform::InputState::Pressed,
+                platform::InputState::Released,
+            ]
+            .iter()
+            {
+                let input = platform::KeyboardInput {
+                    state: *state,
+                    key: Some(key),
+                    modifiers,
+                };
+                let s = String::from(Event::KeyboardInput(input));
+
+                match Event::from_str(&s) {
+                    Ok(Event::KeyboardInput(output)) => assert_eq!(input, output, "{}", s),
+                    other => panic!("{}: unexpected {:?}", s, other),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use rgx::core::Rgba8;
+use rgx::math::{Point2, Vector2};
+
+use std::fmt;
+use std::str::FromStr;
+
+/// 4x4 Bayer matrix, used for ordered dithering.
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Gradient shape.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GradientShape {
+    /// Colors change along the drag direction.
+    Linear,
+    /// Colors change with the distance from the drag origin.
+    Radial,
+}
+
+impl fmt::Display for GradientShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Linear => "linear".fmt(f),
+            Self::Radial => "radial".fmt(f),
+        }
+    }
+}
+
+impl FromStr for GradientShape {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "linear" => Ok(Self::Linear),
+            "radial" => Ok(Self::Radial),
+            other => Err(format!("unknown gradient shape `{}`", other)),
+        }
+    }
+}
+
+/// A gradient suitable for pixel art. Colors are never interpolated: pixels
+/// are either assigned one of the gradient steps, or ordered-dithered between
+/// the two end colors.
+#[derive(Debug, Clone)]
+pub struct Gradient {
+    /// Gradient shape.
+    pub shape: GradientShape,
+    /// Where the gradient starts, in view coordinates.
+    pub from: Point2<i32>,
+    /// Where the gradient ends, in view coordinates.
+    pub to: Point2<i32>,
+    /// Color steps, from start to end. Must not be empty.
+    pub colors: Vec<Rgba8>,
+    /// Whether to dither between the first and last color.
+    pub dither: bool,
+}
+
+impl Gradient {
+    /// Get the gradient color of the given pixel.
+    pub fn color(&self, p: Point2<i32>) -> Rgba8 {
+        let t = self.position(p);
+
+        if self.dither {
+            let threshold =
+                (BAYER[p.y.rem_euclid(4) as usize][p.x.rem_euclid(4) as usize] as f32 + 0.5) / 16.;
+            let (first, last) = (self.colors[0], self.colors[self.colors.len() - 1]);
+
+            if t > threshold {
+                last
+            } else {
+                first
+            }
+        } else {
+            let n = self.colors.len();
+            self.colors[usize::min((t * n as f32) as usize, n - 1)]
+        }
+    }
+
+    /// Position of a pixel along the gradient, from `0.0` to `1.0`.
+    fn position(&self, p: Point2<i32>) -> f32 {
+        let dir = Vector2::new(
+            (self.to.x - self.from.x) as f32,
+            (self.to.y - self.from.y) as f32,
+        );
+        let len = dir.magnitude();
+
+        if len == 0. {
+            return 0.;
+        }
+        let v = Vector2::new((p.x - self.from.x) as f32, (p.y - self.from.y) as f32);
+
+        let t = match self.shape {
+            GradientShape::Linear => Vector2::dot(v, dir) / (len * len),
+            GradientShape::Radial => v.magnitude() / len,
+        };
+        t.clamp(0., 1.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const K: Rgba8 = Rgba8::BLACK;
+    const W: Rgba8 = Rgba8::WHITE;
+
+    fn gradient(shape: GradientShape, to: (i32, i32), colors: &[Rgba8], dither: bool) -> Gradient {
+        Gradient {
+            shape,
+            from: Point2::new(0, 0),
+            to: Point2::new(to.0, to.1),
+            colors: colors.to_vec(),
+            dither,
+        }
+    }
+
+    fn row(g: &Gradient, xs: std::ops::Range<i32>, y: i32) -> Vec<Rgba8> {
+        xs.map(|x| g.color(Point2::new(x, y))).collect()
+    }
+
+    #[test]
+    fn test_shape_parse() {
+        for shape in [GradientShape::Linear, GradientShape::Radial].iter() {
+            assert_eq!(shape.to_string().parse::<GradientShape>(), Ok(*shape));
+        }
+        assert!("diamond".parse::<GradientShape>().is_err());
+    }
+
+    #[test]
+    fn test_linear() {
+        let (r, g, b) = (Rgba8::RED, Rgba8::GREEN, Rgba8::BLUE);
+        let grad = gradient(GradientShape::Linear, (3, 0), &[r, g, b], false);
+
+        // Pixels before the start and past the end get the end colors.
+        assert_eq!(row(&grad, -1..5, 0), vec![r, r, g, b, b, b]);
+        // Only the position along the drag direction matters.
+        assert_eq!(row(&grad, -1..5, 7), row(&grad, -1..5, 0));
+
+        let grad = gradient(GradientShape::Linear, (4, 0), &[K, W], false);
+        assert_eq!(row(&grad, 0..5, 0), vec![K, K, W, W, W]);
+    }
+
+    #[test]
+    fn test_radial() {
+        let (r, g) = (Rgba8::RED, Rgba8::GREEN);
+        let grad = gradient(GradientShape::Radial, (0, 4), &[r, g], false);
+
+        assert_eq!(grad.color(Point2::new(0, 0)), r);
+        assert_eq!(grad.color(Point2::new(1, 0)), r);
+        assert_eq!(grad.color(Point2::new(3, 0)), g);
+        assert_eq!(grad.color(Point2::new(0, -3)), g);
+        assert_eq!(grad.color(Point2::new(5, 5)), g);
+    }
+
+    #[test]
+    fn test_empty_direction() {
+        let grad = gradient(GradientShape::Linear, (0, 0), &[K, W], false);
+
+        assert_eq!(row(&grad, -2..3, 0), vec![K; 5]);
+    }
+
+    #[test]
+    fn test_dither() {
+        let grad = gradient(GradientShape::Linear, (0, 2), &[K, Rgba8::RED, W], true);
+
+        // Dithering is between the first and last colors only.
+        assert_eq!(row(&grad, 0..4, 0), vec![K; 4]);
+        assert_eq!(row(&grad, 0..4, 1), vec![K, W, K, W]);
+        assert_eq!(row(&grad, 0..4, 2), vec![W; 4]);
+        // The pattern repeats every four pixels.
+        assert_eq!(row(&grad, 4..8, 1), row(&grad, 0..4, 1));
+    }
+}
@@ -17,6 +17,7 @@ pub mod execution;
 pub mod session;
 
 mod alloc;
+mod bitmap;
 mod brush;
 mod cmd;
 mod color;
@@ -26,7 +27,9 @@ mod draw;
 mod event;
 mod font;
 mod framebuffer2d;
+mod gradient;
 mod image;
+mod mask;
 mod palette;
 mod parser;
 mod platform;
@@ -113,8 +116,13 @@ pub fn init<P: AsRef<Path>>(paths: &[P], options: Options) -> std::io::Result<()
     let resources = ResourceManager::new();
     let base_dirs = dirs::ProjectDirs::from("io", "cloudhead", "rx")
         .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "home directory not found"))?;
-    let mut session = Session::new(win_w, win_h, hidpi_factor, resources.clone(), base_dirs)
-        .init(options.source.clone())?;
+    let session = Session::new(win_w, win_h, hidpi_factor, resources.clone(), base_dirs);
+    // Replays and recordings shouldn't depend on, or change, the command history.
+    let session = match options.exec {
+        ExecutionMode::Normal => session,
+        _ => session.with_history(None),
+    };
+    let mut session = session.init(options.source.clone())?;
 
     if options.debug {
         session
@@ -0,0 +1,278 @@
+use crate::brush::Brush;
+
+use rgx::core::Rect;
+use rgx::math::Point2;
+
+/// How a new selection is combined with the existing one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MaskOp {
+    /// Replace the existing selection.
+    Replace,
+    /// Add to the existing selection.
+    Add,
+    /// Subtract from the existing selection.
+    Subtract,
+}
+
+/// A mask of selected pixels, used for non-rectangular selections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mask {
+    /// Area covered by the mask, in view coordinates.
+    pub rect: Rect<i32>,
+    /// Mask bits, row by row, from the bottom row to the top.
+    bits: Vec<bool>,
+}
+
+impl Mask {
+    /// Create an empty mask covering the given area.
+    pub fn new(rect: Rect<i32>) -> Self {
+        let rect = rect.abs();
+        let bits = vec![false; (rect.width() * rect.height()) as usize];
+
+        Self { rect, bits }
+    }
+
+    /// Create a mask with all pixels of the given area selected.
+    pub fn from_rect(rect: Rect<i32>) -> Self {
+        let mut mask = Self::new(rect);
+        mask.bits.iter_mut().for_each(|b| *b = true);
+        mask
+    }
+
+    /// Create a mask covering the given area, selecting the pixels for which
+    /// the predicate returns `true`.
+    pub fn from_fn<F>(rect: Rect<i32>, f: F) -> Self
+    where
+        F: Fn(Point2<i32>) -> bool,
+    {
+        let mut mask = Self::new(rect);
+
+        for y in mask.rect.y1..mask.rect.y2 {
+            for x in mask.rect.x1..mask.rect.x2 {
+                let p = Point2::new(x, y);
+                mask.set(p, f(p));
+            }
+        }
+        mask
+    }
+
+    /// Create a mask from a closed polygon, eg. a lasso selection. Pixels
+    /// whose centers lie inside the polygon are selected, as well as the pixels
+    /// on the polygon outline.
+    pub fn polygon(points: &[Point2<i32>]) -> Option<Self> {
+        let (first, rest) = points.split_first()?;
+        let (mut min, mut max) = (*first, *first);
+
+        for p in rest {
+            min = Point2::new(min.x.min(p.x), min.y.min(p.y));
+            max = Point2::new(max.x.max(p.x), max.y.max(p.y));
+        }
+        let mut mask = Self::new(Rect::new(min.x, min.y, max.x + 1, max.y + 1));
+
+        // Fill the inside of the polygon, using the even-odd rule.
+        for y in mask.rect.y1..mask.rect.y2 {
+            let cy = y as f32 + 0.5;
+            let mut crossings = Vec::new();
+
+            for (i, a) in points.iter().enumerate() {
+                let b = points[(i + 1) % points.len()];
+                let (ay, by) = (a.y as f32 + 0.5, b.y as f32 + 0.5);
+
+                if (ay <= cy) != (by <= cy) {
+                    let (ax, bx) = (a.x as f32 + 0.5, b.x as f32 + 0.5);
+                    crossings.push(ax + (cy - ay) / (by - ay) * (bx - ax));
+                }
+            }
+            crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+            for span in crossings.chunks(2) {
+                if let [x1, x2] = span {
+                    for x in mask.rect.x1..mask.rect.x2 {
+                        let cx = x as f32 + 0.5;
+                        if cx >= *x1 && cx < *x2 {
+                            mask.set(Point2::new(x, y), true);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Add the outline.
+        let mut outline = Vec::new();
+        for (i, a) in points.iter().enumerate() {
+            Brush::line(*a, points[(i + 1) % points.len()], &mut outline);
+        }
+        for p in outline {
+            mask.set(p, true);
+        }
+        Some(mask)
+    }
+
+    /// Check whether a pixel is selected.
+    pub fn contains(&self, p: Point2<i32>) -> bool {
+        self.index(p).is_some_and(|i| self.bits[i])
+    }
+
+    /// Select or unselect a pixel. Pixels outside of the mask area are ignored.
+    pub fn set(&mut self, p: Point2<i32>, selected: bool) {
+        if let Some(i) = self.index(p) {
+            self.bits[i] = selected;
+        }
+    }
+
+    /// Check whether every pixel of the mask area is selected.
+    pub fn is_full(&self) -> bool {
+        self.bits.iter().all(|b| *b)
+    }
+
+    /// Move the mask by the given amount.
+    pub fn translate(&mut self, x: i32, y: i32) {
+        self.rect = Rect::new(
+            self.rect.x1 + x,
+            self.rect.y1 + y,
+            self.rect.x2 + x,
+            self.rect.y2 + y,
+        );
+    }
+
+    /// Combine this mask with another one.
+    pub fn combine(&self, other: &Mask, op: MaskOp) -> Mask {
+        match op {
+            MaskOp::Replace => other.clone(),
+            MaskOp::Add => {
+                let rect = Rect::new(
+                    self.rect.x1.min(other.rect.x1),
+                    self.rect.y1.min(other.rect.y1),
+                    self.rect.x2.max(other.rect.x2),
+                    self.rect.y2.max(other.rect.y2),
+                );
+                Mask::from_fn(rect, |p| self.contains(p) || other.contains(p))
+            }
+            MaskOp::Subtract => {
+                Mask::from_fn(self.rect, |p| self.contains(p) && !other.contains(p))
+            }
+        }
+    }
+
+    /// Shrink the mask area to the smallest rectangle containing all selected
+    /// pixels. Returns `None` if no pixels are selected.
+    pub fn trim(&self) -> Option<Mask> {
+        let mut selected = self.pixels();
+        let first = selected.next()?;
+        let (min, max) = selected.fold((first, first), |(min, max), p| {
+            (
+                Point2::new(min.x.min(p.x), min.y.min(p.y)),
+                Point2::new(max.x.max(p.x), max.y.max(p.y)),
+            )
+        });
+        let rect = Rect::new(min.x, min.y, max.x + 1, max.y + 1);
+
+        Some(Mask::from_fn(rect, |p| self.contains(p)))
+    }
+
+    /// Iterate over the selected pixels.
+    pub fn pixels(&self) -> impl Iterator<Item = Point2<i32>> + '_ {
+        let w = self.rect.width();
+
+        self.bits
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b)
+            .map(move |(i, _)| {
+                Point2::new(self.rect.x1 + i as i32 % w, self.rect.y1 + i as i32 / w)
+            })
+    }
+
+    /// Return the outline of the selected pixels, as a list of unit-length
+    /// segments along pixel edges.
+    pub fn outline(&self) -> Vec<(Point2<i32>, Point2<i32>)> {
+        let mut segments = Vec::new();
+
+        for p in self.pixels() {
+            let (x, y) = (p.x, p.y);
+
+            if !self.contains(Point2::new(x - 1, y)) {
+                segments.push((Point2::new(x, y), Point2::new(x, y + 1)));
+            }
+            if !self.contains(Point2::new(x + 1, y)) {
+                segments.push((Point2::new(x + 1, y), Point2::new(x + 1, y + 1)));
+            }
+            if !self.contains(Point2::new(x, y - 1)) {
+                segments.push((Point2::new(x, y), Point2::new(x + 1, y)));
+            }
+            if !self.contains(Point2::new(x, y + 1)) {
+                segments.push((Point2::new(x, y + 1), Point2::new(x + 1, y + 1)));
+            }
+        }
+        segments
+    }
+
+    fn index(&self, p: Point2<i32>) -> Option<usize> {
+        if self.rect.contains(p) {
+            let w = self.rect.width();
+            Some(((p.y - self.rect.y1) * w + (p.x - self.rect.x1)) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a mask at the origin from rows of pixels, top to bottom, where `x`
+    /// marks a selected pixel.
+    fn mask(rows: &[&str]) -> Mask {
+        let (w, h) = (rows[0].len() as i32, rows.len() as i32);
+
+        Mask::from_fn(Rect::new(0, 0, w, h), |p| {
+            rows[(h - p.y - 1) as usize].as_bytes()[p.x as usize] == b'x'
+        })
+    }
+
+    #[test]
+    fn test_contains() {
+        let m = mask(&["x.", ".."]);
+
+        assert!(m.contains(Point2::new(0, 1)));
+        assert!(!m.contains(Point2::new(0, 0)));
+        assert!(!m.contains(Point2::new(-1, 1)));
+        assert!(!m.contains(Point2::new(0, 2)));
+    }
+
+    #[test]
+    fn test_combine() {
+        let a = mask(&["xx.", "xx.", "..."]);
+        let b = mask(&["...", ".xx", ".xx"]);
+
+        assert_eq!(a.combine(&b, MaskOp::Replace), b);
+        assert_eq!(a.combine(&b, MaskOp::Add), mask(&["xx.", "xxx", ".xx"]));
+        assert_eq!(
+            a.combine(&b, MaskOp::Subtract),
+            mask(&["xx.", "x..", "..."])
+        );
+
+        // The intersection is what's left after subtracting the difference.
+        let diff = a.combine(&b, MaskOp::Subtract);
+        assert_eq!(
+            a.combine(&diff, MaskOp::Subtract),
+            mask(&["...", ".x.", "..."])
+        );
+    }
+
+    #[test]
+    fn test_combine_disjoint() {
+        let a = Mask::from_rect(Rect::new(0, 0, 1, 1));
+        let b = Mask::from_rect(Rect::new(2, 0, 3, 1));
+
+        let union = a.combine(&b, MaskOp::Add);
+        assert_eq!(union.rect, Rect::new(0, 0, 3, 1));
+        assert_eq!(union.pixels().count(), 2);
+        assert!(!union.contains(Point2::new(1, 0)));
+
+        // Subtracting keeps the original area.
+        assert_eq!(a.combine(&b, MaskOp::Subtract), a);
+        assert!(b.combine(&b, MaskOp::Subtract).trim().is_none());
+    }
+}
@@ -2,8 +2,10 @@
 
 use crate::brush::BrushMode;
 use crate::platform;
-use crate::session::{Direction, Mode, VisualState};
+use crate::session::{Axis, Direction, Mode, VisualState};
 
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::path::PathBuf;
 use std::result;
@@ -52,9 +54,17 @@ pub trait Parse<'a>: Sized {
 
 impl<'a> Parse<'a> for Rgba8 {
     fn parse(p: Parser<'a>) -> Result<'a, Self> {
-        let (s, rest) = p.count(7)?; // Expect 7 characters including the '#'
+        let (s, rest) = if p.peek() == Some('$') {
+            p.word()?
+        } else {
+            let (s, rest) = p.count(7)?; // Expect 7 characters including the '#'
+            (Cow::Borrowed(s), rest)
+        };
+        if s.len() < 7 || !s.starts_with('#') || !s.is_ascii() {
+            return Err(Error::new(format!("malformed color value `{}`", s)));
+        }
 
-        match Rgba8::from_str(s) {
+        match Rgba8::from_str(&s) {
             Ok(u) => Ok((u, rest)),
             Err(_) => Err(Error::new(format!("malformed color value `{}`", s))),
         }
@@ -65,7 +75,7 @@ impl<'a> Parse<'a> for u32 {
     fn parse(p: Parser<'a>) -> Result<'a, Self> {
         let (s, rest) = p.word()?;
 
-        match u32::from_str(s) {
+        match u32::from_str(&s) {
             Ok(u) => Ok((u, rest)),
             Err(_) => Err(Error::new("error parsing u32")),
         }
@@ -76,7 +86,7 @@ impl<'a> Parse<'a> for i32 {
     fn parse(p: Parser<'a>) -> Result<'a, Self> {
         let (s, rest) = p.word()?;
 
-        match i32::from_str(s) {
+        match i32::from_str(&s) {
             Ok(u) => Ok((u, rest)),
             Err(_) => Err(Error::new("error parsing i32")),
         }
@@ -87,7 +97,7 @@ impl<'a> Parse<'a> for f64 {
     fn parse(p: Parser<'a>) -> Result<'a, Self> {
         let (s, rest) = p.word()?;
 
-        match f64::from_str(s) {
+        match f64::from_str(&s) {
             Ok(u) => Ok((u, rest)),
             Err(_) => Err(Error::new("error parsing f64")),
         }
@@ -127,7 +137,7 @@ impl<'a> Parse<'a> for (f64, f64) {
 impl<'a> Parse<'a> for char {
     fn parse(p: Parser<'a>) -> Result<'a, Self> {
         if let Some(c) = p.input.chars().next() {
-            Ok((c, Parser::new(&p.input[1..])))
+            Ok((c, p.rest(&p.input[1..])))
         } else {
             Err(Error::new("error parsing char"))
         }
@@ -144,6 +154,8 @@ impl<'a> Parse<'a> for BrushMode {
             "xsym" => Ok((BrushMode::XSym, p)),
             "ysym" => Ok((BrushMode::YSym, p)),
             "xray" => Ok((BrushMode::XRay, p)),
+            "wrap" => Ok((BrushMode::Wrap, p)),
+            "alpha-lock" => Ok((BrushMode::AlphaLock, p)),
             mode => Err(Error::new(format!("unknown brush mode '{}'", mode))),
         }
     }
@@ -164,7 +176,7 @@ impl<'a> Parse<'a> for platform::Key {
 impl<'a> Parse<'a> for platform::InputState {
     fn parse(p: Parser<'a>) -> Result<'a, Self> {
         let (w, p) = p.word()?;
-        match w {
+        match w.as_ref() {
             "pressed" => Ok((platform::InputState::Pressed, p)),
             "released" => Ok((platform::InputState::Released, p)),
             "repeated" => Ok((platform::InputState::Repeated, p)),
@@ -197,20 +209,106 @@ impl<'a> Parse<'a> for Direction {
     }
 }
 
+impl<'a> Parse<'a> for Axis {
+    fn parse(p: Parser<'a>) -> Result<'a, Self> {
+        let (c, p) = p.parse::<char>()?;
+        match c {
+            'x' => Ok((Axis::X, p)),
+            'y' => Ok((Axis::Y, p)),
+            _ => Err(Error::new("axis must be either `x` or `y`")),
+        }
+    }
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Values substituted for `$name` references, eg. variables and settings. They
+/// are looked up as they are referenced.
+pub trait Variables {
+    /// Get the value of a variable, as it would be written in a script.
+    fn var(&self, name: &str) -> Option<String>;
+}
+
+impl Variables for HashMap<String, String> {
+    fn var(&self, name: &str) -> Option<String> {
+        self.get(name).cloned()
+    }
+}
+
+/// Names known while parsing, such as script variables and user-defined commands.
+#[derive(Default, Clone)]
+pub struct Scope<'a> {
+    /// Values substituted for `$name` references.
+    pub vars: Option<&'a dyn Variables>,
+    /// Values substituted for `$1`, `$2` etc. in user-defined commands.
+    pub args: Vec<String>,
+    /// Names of user-defined commands.
+    pub commands: HashSet<String>,
+}
+
+impl<'a> Scope<'a> {
+    /// Look up the value of a `$` reference, given without the `$`.
+    pub fn get(&self, name: &str) -> result::Result<Cow<'_, str>, Error> {
+        if let Ok(n) = name.parse::<usize>() {
+            return n
+                .checked_sub(1)
+                .and_then(|i| self.args.get(i))
+                .map(|arg| Cow::Borrowed(arg.as_str()))
+                .ok_or_else(|| Error::new(format!("missing argument `${}`", n)));
+        }
+        self.vars
+            .and_then(|vars| vars.var(name))
+            .map(Cow::Owned)
+            .ok_or_else(|| Error::new(format!("`${}` is undefined", name)))
+    }
+}
+
+impl fmt::Debug for Scope<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scope")
+            .field("vars", &self.vars.is_some())
+            .field("args", &self.args)
+            .field("commands", &self.commands)
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Parser<'a> {
     input: &'a str,
+    scope: Option<&'a Scope<'a>>,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(input: &'a str) -> Self {
-        Self { input }
+        Self { input, scope: None }
+    }
+
+    /// Create a parser which substitutes `$` references using the given scope.
+    pub fn with_scope(input: &'a str, scope: &'a Scope<'a>) -> Self {
+        Self {
+            input,
+            scope: Some(scope),
+        }
     }
 
     pub fn empty() -> Self {
-        Self { input: "" }
+        Self {
+            input: "",
+            scope: None,
+        }
+    }
+
+    pub fn scope(&self) -> Option<&'a Scope<'a>> {
+        self.scope
+    }
+
+    /// Continue parsing the given input, in the same scope.
+    fn rest(&self, input: &'a str) -> Self {
+        Self {
+            input,
+            scope: self.scope,
+        }
     }
 
     pub fn finish(self) -> Result<'a, ()> {
@@ -230,7 +328,7 @@ impl<'a> Parser<'a> {
             return Ok((String::from(""), parser));
         }
 
-        let mut path = PathBuf::from(path);
+        let mut path = PathBuf::from(path.into_owned());
 
         // Linux and BSD and MacOS use ~ to infer the home directory of a given user
         if cfg!(unix) {
@@ -255,9 +353,15 @@ impl<'a> Parser<'a> {
         self.input.is_empty()
     }
 
+    /// Check whether the current command has no more input, ie. the input is
+    /// empty or the next command in a sequence follows.
+    pub fn is_end(&self) -> bool {
+        self.input.is_empty() || self.input.starts_with('|')
+    }
+
     pub fn sigil(self, c: char) -> Result<'a, char> {
         if self.input.starts_with(c) {
-            Ok((c, Parser::new(&self.input[1..])))
+            Ok((c, self.rest(&self.input[1..])))
         } else {
             Err(Error::new(format!("expected '{}'", c)))
         }
@@ -300,7 +404,7 @@ impl<'a> Parser<'a> {
     }
 
     pub fn leftover(self) -> Result<'a, &'a str> {
-        Ok((self.input, Parser::empty()))
+        Ok((self.input, self.rest("")))
     }
 
     pub fn whitespace(self) -> Result<'a, ()> {
@@ -311,13 +415,21 @@ impl<'a> Parser<'a> {
         T::parse(self)
     }
 
-    pub fn word(self) -> Result<'a, &'a str> {
-        self.expect(|c| !c.is_whitespace() && c != '{' && c != '}')
+    /// Parse a word. In a scope, a word such as `$name` is substituted with
+    /// the value it references, as a whole.
+    pub fn word(self) -> Result<'a, Cow<'a, str>> {
+        let scope = self.scope;
+        let (w, p) = self.expect(|c| !c.is_whitespace() && c != '{' && c != '}' && c != '|')?;
+
+        match (scope, w.strip_prefix('$')) {
+            (Some(scope), Some(name)) if !name.is_empty() => Ok((scope.get(name)?, p)),
+            _ => Ok((Cow::Borrowed(w), p)),
+        }
     }
 
     pub fn count(self, n: usize) -> Result<'a, &'a str> {
         if self.input.len() >= n {
-            Ok((&self.input[..n], Parser::new(&self.input[n..])))
+            Ok((&self.input[..n], self.rest(&self.input[n..])))
         } else {
             Err(Error::new("reached end of input"))
         }
@@ -339,9 +451,9 @@ impl<'a> Parser<'a> {
         match self.input.find(|c| !predicate(c)) {
             Some(i) => {
                 let (_, r) = self.input.split_at(i);
-                Ok(((), Parser::new(r)))
+                Ok(((), self.rest(r)))
             }
-            None => Ok(((), Parser::empty())),
+            None => Ok(((), self.rest(""))),
         }
     }
 
@@ -355,9 +467,9 @@ impl<'a> Parser<'a> {
         match self.input.find(predicate) {
             Some(i) => {
                 let (l, r) = self.input.split_at(i);
-                Ok((l, Parser::new(r)))
+                Ok((l, self.rest(r)))
             }
-            None => Ok((self.input, Parser::empty())),
+            None => Ok((self.input, self.rest(""))),
         }
     }
 
@@ -381,6 +493,6 @@ impl<'a> Parser<'a> {
             }
         }
         let (l, r) = self.input.split_at(index + 1);
-        Ok((l, Parser::new(r)))
+        Ok((l, self.rest(r)))
     }
 }
@@ -240,6 +240,55 @@ impl From<glfw::Key> for Key {
             Glfw::RightControl => Key::Control,
             Glfw::RightShift => Key::Shift,
             Glfw::Tab => Key::Tab,
+            Glfw::LeftSuper => Key::Meta,
+            Glfw::RightSuper => Key::Meta,
+            Glfw::F1 => Key::F1,
+            Glfw::F2 => Key::F2,
+            Glfw::F3 => Key::F3,
+            Glfw::F4 => Key::F4,
+            Glfw::F5 => Key::F5,
+            Glfw::F6 => Key::F6,
+            Glfw::F7 => Key::F7,
+            Glfw::F8 => Key::F8,
+            Glfw::F9 => Key::F9,
+            Glfw::F10 => Key::F10,
+            Glfw::F11 => Key::F11,
+            Glfw::F12 => Key::F12,
+            Glfw::F13 => Key::F13,
+            Glfw::F14 => Key::F14,
+            Glfw::F15 => Key::F15,
+            Glfw::F16 => Key::F16,
+            Glfw::F17 => Key::F17,
+            Glfw::F18 => Key::F18,
+            Glfw::F19 => Key::F19,
+            Glfw::F20 => Key::F20,
+            Glfw::F21 => Key::F21,
+            Glfw::F22 => Key::F22,
+            Glfw::F23 => Key::F23,
+            Glfw::F24 => Key::F24,
+            Glfw::Kp0 => Key::Kp0,
+            Glfw::Kp1 => Key::Kp1,
+            Glfw::Kp2 => Key::Kp2,
+            Glfw::Kp3 => Key::Kp3,
+            Glfw::Kp4 => Key::Kp4,
+            Glfw::Kp5 => Key::Kp5,
+            Glfw::Kp6 => Key::Kp6,
+            Glfw::Kp7 => Key::Kp7,
+            Glfw::Kp8 => Key::Kp8,
+            Glfw::Kp9 => Key::Kp9,
+            Glfw::KpAdd => Key::KpAdd,
+            Glfw::KpSubtract => Key::KpSubtract,
+            Glfw::KpMultiply => Key::KpMultiply,
+            Glfw::KpDivide => Key::KpDivide,
+            Glfw::KpDecimal => Key::KpDecimal,
+            Glfw::KpEnter => Key::KpEnter,
+            Glfw::KpEqual => Key::KpEqual,
+            Glfw::CapsLock => Key::CapsLock,
+            Glfw::NumLock => Key::NumLock,
+            Glfw::ScrollLock => Key::ScrollLock,
+            Glfw::PrintScreen => Key::PrintScreen,
+            Glfw::Pause => Key::Pause,
+            Glfw::Menu => Key::Menu,
 
             _ => {
                 if let Some(sym) = k.get_name() {
@@ -178,13 +178,24 @@ pub enum Key {
     Backspace, Return, Space, Tab,
     Escape, Insert, Home, Delete, End, PageDown, PageUp,
 
+    // Function keys.
+    F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    F13, F14, F15, F16, F17, F18, F19, F20, F21, F22, F23, F24,
+
+    // Keypad keys.
+    Kp0, Kp1, Kp2, Kp3, Kp4, Kp5, Kp6, Kp7, Kp8, Kp9,
+    KpAdd, KpSubtract, KpMultiply, KpDivide, KpDecimal, KpEnter, KpEqual,
+
+    // Lock & system keys.
+    CapsLock, NumLock, ScrollLock, PrintScreen, Pause, Menu,
+
     // Punctuation.
     Apostrophe, Grave, Caret, Comma, Period, Colon, Semicolon,
     LBracket, RBracket,
     Slash, Backslash,
 
     // Modifiers.
-    Alt, Control, Shift,
+    Alt, Control, Shift, Meta,
 
     // Math keys.
     Equal, Minus,
@@ -193,6 +204,20 @@ pub enum Key {
     Unknown,
 }
 
+impl Key {
+    /// The value of a number key, if this is one.
+    #[rustfmt::skip]
+    pub fn digit(self) -> Option<u32> {
+        match self {
+            Key::Num0 => Some(0), Key::Num1 => Some(1), Key::Num2 => Some(2),
+            Key::Num3 => Some(3), Key::Num4 => Some(4), Key::Num5 => Some(5),
+            Key::Num6 => Some(6), Key::Num7 => Some(7), Key::Num8 => Some(8),
+            Key::Num9 => Some(9),
+            _ => None,
+        }
+    }
+}
+
 impl From<char> for Key {
     #[rustfmt::skip]
     fn from(c: char) -> Self {
@@ -214,7 +239,7 @@ impl From<char> for Key {
             '`' => Key::Grave, ',' => Key::Comma, '.' => Key::Period,
             '=' => Key::Equal, '-' => Key::Minus, '\'' => Key::Apostrophe,
             ';' => Key::Semicolon, ':' => Key::Colon, ' ' => Key::Space,
-            '\\' => Key::Backslash,
+            '\\' => Key::Backslash, '^' => Key::Caret,
             _ => Key::Unknown,
         }
     }
@@ -269,6 +294,7 @@ impl fmt::Display for Key {
             Key::Control => "<ctrl>".fmt(f),
             Key::Shift => "<shift>".fmt(f),
             Key::Alt => "<alt>".fmt(f),
+            Key::Meta => "<meta>".fmt(f),
             Key::Up => "<up>".fmt(f),
             Key::Down => "<down>".fmt(f),
             Key::Left => "<left>".fmt(f),
@@ -286,6 +312,53 @@ impl fmt::Display for Key {
             Key::Grave => "`".fmt(f),
             Key::Caret => "^".fmt(f),
             Key::End => "<end>".fmt(f),
+            Key::F1 => "<f1>".fmt(f),
+            Key::F2 => "<f2>".fmt(f),
+            Key::F3 => "<f3>".fmt(f),
+            Key::F4 => "<f4>".fmt(f),
+            Key::F5 => "<f5>".fmt(f),
+            Key::F6 => "<f6>".fmt(f),
+            Key::F7 => "<f7>".fmt(f),
+            Key::F8 => "<f8>".fmt(f),
+            Key::F9 => "<f9>".fmt(f),
+            Key::F10 => "<f10>".fmt(f),
+            Key::F11 => "<f11>".fmt(f),
+            Key::F12 => "<f12>".fmt(f),
+            Key::F13 => "<f13>".fmt(f),
+            Key::F14 => "<f14>".fmt(f),
+            Key::F15 => "<f15>".fmt(f),
+            Key::F16 => "<f16>".fmt(f),
+            Key::F17 => "<f17>".fmt(f),
+            Key::F18 => "<f18>".fmt(f),
+            Key::F19 => "<f19>".fmt(f),
+            Key::F20 => "<f20>".fmt(f),
+            Key::F21 => "<f21>".fmt(f),
+            Key::F22 => "<f22>".fmt(f),
+            Key::F23 => "<f23>".fmt(f),
+            Key::F24 => "<f24>".fmt(f),
+            Key::Kp0 => "<k0>".fmt(f),
+            Key::Kp1 => "<k1>".fmt(f),
+            Key::Kp2 => "<k2>".fmt(f),
+            Key::Kp3 => "<k3>".fmt(f),
+            Key::Kp4 => "<k4>".fmt(f),
+            Key::Kp5 => "<k5>".fmt(f),
+            Key::Kp6 => "<k6>".fmt(f),
+            Key::Kp7 => "<k7>".fmt(f),
+            Key::Kp8 => "<k8>".fmt(f),
+            Key::Kp9 => "<k9>".fmt(f),
+            Key::KpAdd => "<kplus>".fmt(f),
+            Key::KpSubtract => "<kminus>".fmt(f),
+            Key::KpMultiply => "<kmultiply>".fmt(f),
+            Key::KpDivide => "<kdivide>".fmt(f),
+            Key::KpDecimal => "<kpoint>".fmt(f),
+            Key::KpEnter => "<kenter>".fmt(f),
+            Key::KpEqual => "<kequal>".fmt(f),
+            Key::CapsLock => "<capslock>".fmt(f),
+            Key::NumLock => "<numlock>".fmt(f),
+            Key::ScrollLock => "<scrolllock>".fmt(f),
+            Key::PrintScreen => "<printscreen>".fmt(f),
+            Key::Pause => "<pause>".fmt(f),
+            Key::Menu => "<menu>".fmt(f),
             Key::Colon => ":".fmt(f),
             Key::Semicolon => ";".fmt(f),
             Key::Equal => "=".fmt(f),
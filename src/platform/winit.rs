@@ -256,7 +256,6 @@ impl From<winit::event::VirtualKeyCode> for Key {
             Winit::LBracket => Key::LBracket,
             Winit::LControl => Key::Control,
             Winit::LShift => Key::Shift,
-            Winit::Subtract => Key::Minus,
             Winit::Period => Key::Period,
             Winit::RAlt => Key::Alt,
             Winit::RBracket => Key::RBracket,
@@ -265,6 +264,56 @@ impl From<winit::event::VirtualKeyCode> for Key {
             Winit::Semicolon => Key::Semicolon,
             Winit::Slash => Key::Slash,
             Winit::Tab => Key::Tab,
+            Winit::Minus => Key::Minus,
+            Winit::LWin => Key::Meta,
+            Winit::RWin => Key::Meta,
+            Winit::F1 => Key::F1,
+            Winit::F2 => Key::F2,
+            Winit::F3 => Key::F3,
+            Winit::F4 => Key::F4,
+            Winit::F5 => Key::F5,
+            Winit::F6 => Key::F6,
+            Winit::F7 => Key::F7,
+            Winit::F8 => Key::F8,
+            Winit::F9 => Key::F9,
+            Winit::F10 => Key::F10,
+            Winit::F11 => Key::F11,
+            Winit::F12 => Key::F12,
+            Winit::F13 => Key::F13,
+            Winit::F14 => Key::F14,
+            Winit::F15 => Key::F15,
+            Winit::F16 => Key::F16,
+            Winit::F17 => Key::F17,
+            Winit::F18 => Key::F18,
+            Winit::F19 => Key::F19,
+            Winit::F20 => Key::F20,
+            Winit::F21 => Key::F21,
+            Winit::F22 => Key::F22,
+            Winit::F23 => Key::F23,
+            Winit::F24 => Key::F24,
+            Winit::Numpad0 => Key::Kp0,
+            Winit::Numpad1 => Key::Kp1,
+            Winit::Numpad2 => Key::Kp2,
+            Winit::Numpad3 => Key::Kp3,
+            Winit::Numpad4 => Key::Kp4,
+            Winit::Numpad5 => Key::Kp5,
+            Winit::Numpad6 => Key::Kp6,
+            Winit::Numpad7 => Key::Kp7,
+            Winit::Numpad8 => Key::Kp8,
+            Winit::Numpad9 => Key::Kp9,
+            Winit::Add => Key::KpAdd,
+            Winit::Subtract => Key::KpSubtract,
+            Winit::Multiply => Key::KpMultiply,
+            Winit::Divide => Key::KpDivide,
+            Winit::Decimal => Key::KpDecimal,
+            Winit::NumpadEnter => Key::KpEnter,
+            Winit::NumpadEquals => Key::KpEqual,
+            Winit::Capital => Key::CapsLock,
+            Winit::Numlock => Key::NumLock,
+            Winit::Scroll => Key::ScrollLock,
+            Winit::Snapshot => Key::PrintScreen,
+            Winit::Pause => Key::Pause,
+            Winit::Apps => Key::Menu,
             _ => Key::Unknown,
         }
     }
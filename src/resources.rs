@@ -1,3 +1,4 @@
+use crate::bitmap::Bitmap;
 use crate::image;
 use crate::session::Rgb8;
 use crate::view::{ViewExtent, ViewId};
@@ -104,12 +105,18 @@ pub struct ResourceManager {
 
 pub struct Resources {
     data: BTreeMap<ViewId, ViewResources>,
+    /// The paste buffer, holding the last yanked pixels.
+    pub paste: Option<Bitmap>,
+    /// Named yank registers.
+    pub registers: BTreeMap<char, Bitmap>,
 }
 
 impl Resources {
     fn new() -> Self {
         Self {
             data: BTreeMap::new(),
+            paste: None,
+            registers: BTreeMap::new(),
         }
     }
 
@@ -202,6 +209,10 @@ impl ResourceManager {
         Ok((width, height, pixels.into()))
     }
 
+    pub fn save_image<P: AsRef<Path>>(path: P, w: u32, h: u32, pixels: &[Rgba8]) -> io::Result<()> {
+        image::save(path, w, h, pixels)
+    }
+
     pub fn save_view<P: AsRef<Path>>(
         &self,
         id: ViewId,
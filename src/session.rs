@@ -1,14 +1,19 @@
 ///! Session
+use crate::bitmap::Bitmap;
 use crate::brush::*;
-use crate::cmd::{self, Command, CommandLine, Key, KeyMapping, Op, Value};
-use crate::color;
+use crate::cmd::{self, Command, CommandLine, Completion, Expr, Key, KeyMapping, Op, Value};
+use crate::color::{self, Blend};
 use crate::data;
 use crate::event::{Event, TimedEvent};
 use crate::execution::{DigestMode, DigestState, Execution};
+use crate::gradient::{Gradient, GradientShape};
 use crate::hashmap;
+use crate::mask::{Mask, MaskOp};
 use crate::palette::*;
+use crate::parser::{Scope, Variables};
 use crate::platform::{self, InputState, KeyboardInput, LogicalSize, ModifiersState};
 use crate::resources::{Pixels, ResourceManager};
+use crate::util;
 use crate::view::{FileStatus, View, ViewCoords, ViewId, ViewManager};
 
 use rgx::core::{Blending, PresentMode, Rect};
@@ -19,7 +24,7 @@ use rgx::math::*;
 use directories as dirs;
 
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fs::File;
 use std::io;
@@ -45,6 +50,18 @@ pub const HELP: &str = r#"
 :slice <n>               Slice view into <n> frames
 :source <path>           Source an rx script (eg. a palette or config)
 :map <key> <command>     Map a key combination to a command
+:map <ctrl>z <command>   Map a key with modifiers held to a command
+:map g g <command>       Map a key sequence to a command
+:<cmd> | <cmd> ...       Run commands in sequence, stopping at the first error
+:undo <n>                Undo <n> times, any repeatable command takes a count
+:repeat                  Repeat the last edit, eg. a fill or paste
+:def <name> <cmds>       Define command :<name>, taking arguments $1, $2...
+:def                     List user-defined commands
+:let <name> = <expr>     Set variable $<name>, eg. `:let size = $v/width / 2`
+:if <expr>               Run the following commands if <expr> is true,
+:else                    otherwise run the ones after `:else`,
+:end                     up until `:end`
+:<cmd> $<name>           Pass the value of a variable or setting, eg. `:f/resize $w $h`
 :f/resize <w> <h>        Resize frames
 :f/add                   Add a blank frame to the view
 :f/remove                Remove the last frame of the view
@@ -52,8 +69,25 @@ pub const HELP: &str = r#"
 :f/clone                 Clone the last frame and add it to the view
 :p/clear                 Clear the palette
 :p/add <color>           Add <color> to the palette, eg. #ff0011
-:brush/set <mode>        Set brush mode, eg. `xsym` and `ysym` for symmetry
+:brush/set <mode>        Set brush mode, eg. `xsym` and `ysym` for symmetry,
+                         `wrap` for seamless tiling or `alpha-lock`
 :brush/unset <mode>      Unset brush mode
+:selection/yank [<r>]    Yank the selection, optionally into register <r>
+:selection/cut [<r>]     Cut the selection, optionally into register <r>
+:selection/paste [<r>]   Paste, or start pasting the contents of register <r>
+:registers               List yank registers
+:history [<n>]           List the last <n> commands entered
+:selection/write <path>  Write the selection to <path> (.png)
+:selection/new           Open the selection in a new view
+:selection/flip x|y      Flip the selection, or the paste buffer when pasting
+:selection/cell <x> <y>  Select grid cell <x>,<y>, counting from the bottom-left
+:selection/shift <x> <y> Shift the selected pixels, wrapping around the edges
+:selection/rotate <deg>  Rotate the selection clockwise by the given angle
+:selection/scale <x> [<y>]  Scale the selection, or the paste buffer when pasting
+:v/scale <x> [<y>]       Scale the view and its frames
+:outline [<color>]       Outline the selection or frames with <color> or fg
+:gradient                Gradient tool: drag to fill the selection or area with fg..bg
+:gradient <x1> <y1> <x2> <y2>  Fill the selection or area with fg..bg, from x1,y1 to x2,y2
 
 SETTINGS
 
@@ -61,10 +95,28 @@ debug             on/off             Debug mode
 checker           on/off             Alpha checker toggle
 vsync             on/off             Vertical sync toggle
 input/delay       0.0..32.0          Delay between render frames (ms)
+input/timeout     0..                Time to wait for the next key in a sequence (ms)
 scale             1.0..4.0           UI scale
 animation         on/off             View animation toggle
 animation/delay   1..1000            View animation delay (ms)
 background        #000000..#ffffff   Set background appearance to <color>, eg. #ff0011
+tile-preview      on/off             Show the frame under the cursor tiled 3x3
+brush/opacity     0.0..1.0           Brush opacity
+brush/blend       over/replace/...   Brush blend mode (over, replace, multiply, add)
+brush/smoothing   0..64              Brush stabilizer string length (px), 0 to disable
+gradient/shape    linear/radial      Gradient tool shape
+gradient/dither   on/off             Dither gradients between fg and bg instead of stepping through the palette
+selection/tool    rect/lasso/wand    Visual mode selection tool. Hold <shift> to add, <ctrl> to subtract
+selection/tolerance   0..255         Magic wand color tolerance
+selection/contiguous  on/off         Magic wand selects contiguous pixels only
+paste/transparent on/off             Don't paste fully transparent pixels
+paste/behind      on/off             Only paste over transparent pixels
+paste/colorkey    on/off             Don't paste pixels of the background color
+selection/float   on/off             Moving the selection moves the selected pixels
+outline/diagonal  on/off             Outline diagonally adjacent pixels too
+outline/inside    on/off             Outline inside of the opaque pixels
+grid/snap         on/off             Snap selections and pasting to the grid
+scale/epx         on/off             Use Scale2x/Scale3x (EPX) when scaling by 2 or 3
 "#;
 
 /// An RGB 8-bit color. Used when the alpha value isn't used.
@@ -239,6 +291,64 @@ impl Deref for Selection {
     }
 }
 
+/// The tool used to make selections in visual mode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SelectionTool {
+    /// Rectangular selection.
+    Rect,
+    /// Freehand polygon selection.
+    Lasso,
+    /// Selection by color.
+    Wand,
+}
+
+impl fmt::Display for SelectionTool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rect => "rect".fmt(f),
+            Self::Lasso => "lasso".fmt(f),
+            Self::Wand => "wand".fmt(f),
+        }
+    }
+}
+
+impl FromStr for SelectionTool {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "rect" => Ok(Self::Rect),
+            "lasso" => Ok(Self::Lasso),
+            "wand" => Ok(Self::Wand),
+            other => Err(format!("unknown selection tool `{}`", other)),
+        }
+    }
+}
+
+/// A selection in the making. Once the mouse button is released, it is
+/// combined with the selection that existed when it was started.
+#[derive(Debug, Clone)]
+pub struct SelectionDraft {
+    /// How the new selection is combined with the existing one.
+    pub op: MaskOp,
+    /// The selection before the draft was started.
+    pub base: Option<Mask>,
+    /// Lasso points, in view coordinates. Empty for rectangular selections.
+    pub lasso: Vec<Point2<i32>>,
+}
+
+/// The paste buffer as it was before being rotated by an arbitrary angle.
+/// Further rotations start from it, so that they don't compound.
+#[derive(Debug, Clone)]
+struct PasteRotation {
+    /// Pixels before rotation.
+    pixels: Bitmap,
+    /// Selected pixels before rotation, if the selection has a mask.
+    selected: Option<Bitmap>,
+    /// Angle the paste buffer is currently rotated by, in degrees.
+    degrees: f32,
+}
+
 /// Session effects. Eg. view creation/destruction.
 /// Anything the renderer might want to know.
 #[derive(Clone, Debug)]
@@ -263,6 +373,8 @@ pub enum Effect {
     ViewPaintFinal(Vec<Shape>),
     /// The blend mode used for painting has changed.
     ViewBlendingChanged(Blending),
+    /// The paste buffer has changed.
+    PasteBufferChanged,
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -299,6 +411,8 @@ pub enum Tool {
     Sampler,
     /// Used to pan the workspace.
     Pan(PanState),
+    /// Used to fill an area with a gradient.
+    Gradient(GradientState),
 }
 
 impl Default for Tool {
@@ -319,6 +433,20 @@ impl Default for PanState {
     }
 }
 
+/// Input state of the gradient tool.
+#[derive(Debug, Clone, Copy)]
+pub enum GradientState {
+    /// Dragging from the given point, where the gradient starts.
+    Dragging(ViewCoords<i32>),
+    NotDragging,
+}
+
+impl Default for GradientState {
+    fn default() -> Self {
+        Self::NotDragging
+    }
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
 /// A generic direction that can be used for things that go backward
@@ -338,6 +466,24 @@ impl From<Direction> for i32 {
     }
 }
 
+/// An axis, used when flipping pixels.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Axis {
+    /// Flip along the horizontal axis, mirroring left and right.
+    X,
+    /// Flip along the vertical axis, mirroring top and bottom.
+    Y,
+}
+
+impl fmt::Display for Axis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::X => "x".fmt(f),
+            Self::Y => "y".fmt(f),
+        }
+    }
+}
+
 /// A message to the user, displayed in the session.
 pub struct Message {
     /// The message string.
@@ -368,6 +514,10 @@ impl Message {
         self.message_type == MessageType::Debug
     }
 
+    pub fn is_error(&self) -> bool {
+        self.message_type == MessageType::Error
+    }
+
     /// Log a message to stdout/stderr.
     fn log(&self) {
         match self.message_type {
@@ -446,6 +596,8 @@ pub struct KeyBinding {
     pub modifiers: ModifiersState,
     /// Key which must be pressed or released.
     pub key: Key,
+    /// Keys which must have been pressed before `key`, in order.
+    pub sequence: Vec<(ModifiersState, Key)>,
     /// Whether the key should be pressed or released.
     pub state: InputState,
     /// The `Command` to run when this binding is triggered.
@@ -479,6 +631,7 @@ impl Default for KeyBindings {
                         meta: false,
                     },
                     key: Key::Virtual(platform::Key::Slash),
+                    sequence: Vec::new(),
                     state: InputState::Pressed,
                     command: Command::Mode(Mode::Help),
                     is_toggle: false,
@@ -497,6 +650,7 @@ impl Default for KeyBindings {
                         meta: false,
                     },
                     key: Key::Virtual(platform::Key::Semicolon),
+                    sequence: Vec::new(),
                     state: InputState::Pressed,
                     command: Command::Mode(Mode::Command),
                     is_toggle: false,
@@ -512,6 +666,7 @@ impl Default for KeyBindings {
                         meta: false,
                     },
                     key: Key::Virtual(platform::Key::Colon),
+                    sequence: Vec::new(),
                     state: InputState::Pressed,
                     command: Command::Mode(Mode::Command),
                     is_toggle: false,
@@ -528,19 +683,38 @@ impl KeyBindings {
         self.elems.push(binding);
     }
 
-    /// Find a key binding based on some input state.
+    /// Find a key binding based on some input state, and the keys pressed
+    /// before `key` as part of a sequence.
     pub fn find(
         &self,
         key: Key,
         modifiers: ModifiersState,
         state: InputState,
         mode: Mode,
+        sequence: &[(ModifiersState, Key)],
     ) -> Option<KeyBinding> {
         self.elems.iter().rev().cloned().find(|kb| {
             kb.key == key
                 && kb.state == state
                 && kb.modes.contains(&mode)
-                && (kb.modifiers == modifiers || state == InputState::Released)
+                && ((kb.modifiers == modifiers && kb.sequence == sequence)
+                    || state == InputState::Released)
+        })
+    }
+
+    /// Check whether there is a key binding for a longer sequence of keys,
+    /// starting with the given ones.
+    pub fn is_prefix(&self, sequence: &[(ModifiersState, Key)], mode: Mode) -> bool {
+        self.elems.iter().any(|kb| {
+            kb.state == InputState::Pressed
+                && kb.modes.contains(&mode)
+                && kb.sequence.len() >= sequence.len()
+                && kb
+                    .sequence
+                    .iter()
+                    .chain(std::iter::once(&(kb.modifiers, kb.key)))
+                    .zip(sequence)
+                    .all(|(a, b)| a == b)
         })
     }
 
@@ -570,6 +744,14 @@ impl Settings {
         }
     }
 
+    /// Iterate over the setting names.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.map
+            .keys()
+            .map(String::as_str)
+            .filter(|k| !Self::DEPRECATED.contains(k))
+    }
+
     /// Lookup a setting.
     pub fn get(&self, setting: &str) -> Option<&Value> {
         self.map.get(setting)
@@ -580,6 +762,8 @@ impl Settings {
     /// old value.
     pub fn set(&mut self, k: &str, v: Value) -> Result<Value, Error> {
         if let Some(current) = self.get(k) {
+            let v = v.convert(current)?;
+
             if std::mem::discriminant(&v) == std::mem::discriminant(current) {
                 return Ok(self.map.insert(k.to_string(), v).unwrap());
             }
@@ -605,6 +789,7 @@ impl Default for Settings {
                 "vsync" => Value::Bool(false),
                 "input/delay" => Value::F32(8.0),
                 "input/mouse" => Value::Bool(true),
+                "input/timeout" => Value::U32(1000),
                 "scale" => Value::F32(1.0),
                 "animation" => Value::Bool(true),
                 "animation/delay" => Value::U32(160),
@@ -618,6 +803,30 @@ impl Default for Settings {
                 "grid" => Value::Bool(false),
                 "grid/color" => Value::Rgba8(color::BLUE),
                 "grid/spacing" => Value::U32Tuple(8, 8),
+                "grid/snap" => Value::Bool(false),
+
+                "tile-preview" => Value::Bool(false),
+
+                "brush/opacity" => Value::F32(1.0),
+                "brush/blend" => Value::Blend(Blend::Over),
+                "brush/smoothing" => Value::U32(0),
+
+                "gradient/shape" => Value::GradientShape(GradientShape::Linear),
+                "gradient/dither" => Value::Bool(false),
+
+                "selection/tool" => Value::SelectionTool(SelectionTool::Rect),
+                "selection/tolerance" => Value::U32(0),
+                "selection/contiguous" => Value::Bool(true),
+                "selection/float" => Value::Bool(false),
+
+                "outline/diagonal" => Value::Bool(false),
+                "outline/inside" => Value::Bool(false),
+
+                "scale/epx" => Value::Bool(false),
+
+                "paste/transparent" => Value::Bool(false),
+                "paste/behind" => Value::Bool(false),
+                "paste/colorkey" => Value::Bool(false),
 
                 // Deprecated.
                 "frame_delay" => Value::F32(0.0)
@@ -680,6 +889,8 @@ pub struct Session {
 
     /// Directories in which user configuration is stored.
     base_dirs: dirs::ProjectDirs,
+    /// File the command-line history is kept in, if it is kept between sessions.
+    history: Option<PathBuf>,
 
     /// Resources shared with the `Renderer`.
     resources: ResourceManager,
@@ -693,6 +904,36 @@ pub struct Session {
 
     /// Current pixel selection.
     pub selection: Option<Selection>,
+    /// Mask of the current selection, for selections which aren't rectangular.
+    selection_mask: Option<Mask>,
+    /// Selection in the making, if any.
+    pub selection_draft: Option<SelectionDraft>,
+    /// Whether the selected pixels are lifted off the view, see `selection/float`.
+    pub floating: bool,
+    /// Pixels lifted off the view, which are cleared once the selection is dropped.
+    lifted: Option<Mask>,
+    /// The paste buffer before it was rotated, see `selection/rotate`.
+    rotation: Option<PasteRotation>,
+    /// Count typed before a key binding, eg. `5` in `5u`.
+    pub count: Option<u32>,
+    /// Keys pressed so far as part of a key sequence, eg. the first `g` in `g g`.
+    key_sequence: Vec<(ModifiersState, Key)>,
+    /// Time since the last key of the sequence was pressed.
+    key_sequence_elapsed: time::Duration,
+    /// The last command which edited the view, run again by `:repeat`.
+    last_edit: Option<Command>,
+    /// Number of edit commands run so far.
+    edits: usize,
+
+    /// User-defined commands, defined with `:def`.
+    definitions: BTreeMap<String, String>,
+    /// How deeply nested the user-defined command being run is.
+    call_depth: usize,
+    /// Script variables, defined with `:let`.
+    variables: HashMap<String, Value>,
+    /// Conditional blocks being run, innermost last. Each block records whether
+    /// its current branch is being run, and whether any of its branches has been.
+    conditions: Vec<(bool, bool)>,
 
     /// The session's current settings.
     pub settings: Settings,
@@ -717,6 +958,9 @@ pub struct Session {
     pub tool: Tool,
     /// The previous tool, if any.
     pub prev_tool: Option<Tool>,
+    /// Stabilized cursor position while drawing with `brush/smoothing`.
+    /// This is where the paint actually lands.
+    pub stabilized_cursor: Option<SessionCoords>,
 
     /// Input state of the mouse.
     mouse_state: InputState,
@@ -748,6 +992,12 @@ impl Session {
     const MIN_BRUSH_SIZE: usize = 1;
     /// Maximum frame width or height.
     const MAX_FRAME_SIZE: u32 = 4096;
+    /// Maximum nesting of user-defined commands.
+    const MAX_CALL_DEPTH: usize = 64;
+    /// Session and view properties, which scripts can reference, eg. `$v/width`.
+    const PROPERTIES: &'static [&'static str] = &[
+        "s/hidpi", "v/width", "v/height", "v/fw", "v/fh", "v/frames", "v/zoom",
+    ];
     /// Maximum zoom amount as a multiplier.
     const MAX_ZOOM: f32 = 128.0;
     /// Zoom levels used when zooming in/out.
@@ -770,6 +1020,8 @@ impl Session {
 
     /// Name of rx initialization script.
     const INIT: &'static str = "init.rx";
+    /// Name of the command-line history file.
+    const HISTORY: &'static str = "history";
 
     /// Create a new un-initialized session.
     pub fn new(
@@ -779,6 +1031,8 @@ impl Session {
         resources: ResourceManager,
         base_dirs: dirs::ProjectDirs,
     ) -> Self {
+        let history = Some(base_dirs.data_dir().join(Self::HISTORY));
+
         Self {
             state: State::Initializing,
             width: w as f32,
@@ -786,9 +1040,11 @@ impl Session {
             hidpi_factor,
             cursor: SessionCoords::new(0., 0.),
             base_dirs,
+            history,
             offset: Vector2::zero(),
             tool: Tool::default(),
             prev_tool: Option::default(),
+            stabilized_cursor: None,
             mouse_state: InputState::Released,
             hover_color: Option::default(),
             hover_view: Option::default(),
@@ -806,6 +1062,20 @@ impl Session {
             mode: Mode::Normal,
             prev_mode: Option::default(),
             selection: Option::default(),
+            selection_mask: None,
+            selection_draft: None,
+            floating: false,
+            lifted: None,
+            rotation: None,
+            definitions: BTreeMap::new(),
+            call_depth: 0,
+            count: None,
+            key_sequence: Vec::new(),
+            key_sequence_elapsed: time::Duration::from_secs(0),
+            last_edit: None,
+            edits: 0,
+            variables: HashMap::new(),
+            conditions: Vec::new(),
             message: Message::default(),
             resources,
             avg_time: time::Duration::from_secs(0),
@@ -825,6 +1095,8 @@ impl Session {
             // The special source '-' is used to skip initialization.
             if init.as_os_str() != "-" {
                 self.source_path(&init)?;
+            } else {
+                self.history = None;
             }
         } else {
             let dir = self.base_dirs.config_dir().to_owned();
@@ -835,11 +1107,24 @@ impl Session {
             }
         }
         self.source_dir(cwd).ok();
+
+        if let Some(history) = &self.history {
+            if let Err(e) = self.cmdline.load_history(history) {
+                warn!("error loading history from {}: {}", history.display(), e);
+            }
+        }
         self.message(format!("rx v{}", crate::VERSION), MessageType::Debug);
 
         Ok(self)
     }
 
+    /// Set the file the command-line history is kept in, or `None` to not keep
+    /// it between sessions.
+    pub fn with_history(mut self, history: Option<PathBuf>) -> Self {
+        self.history = history;
+        self
+    }
+
     // Reset to factory defaults.
     pub fn reset(&mut self) -> io::Result<()> {
         self.key_bindings = KeyBindings::default();
@@ -890,6 +1175,17 @@ impl Session {
             b.update();
         }
 
+        // Key sequences which aren't completed in time are run as they are.
+        if !self.key_sequence.is_empty() {
+            self.key_sequence_elapsed += delta;
+
+            if self.key_sequence_elapsed.as_millis() as u64
+                >= self.settings["input/timeout"].uint64()
+            {
+                self.flush_key_sequence();
+            }
+        }
+
         for (_, v) in self.views.iter_mut() {
             v.okay();
 
@@ -994,17 +1290,14 @@ impl Session {
         }
 
         if let Tool::Brush(ref brush) = self.tool {
-            let output = brush.output(
-                Stroke::NONE,
-                Fill::Solid(brush.color.into()),
-                1.0,
-                Origin::BottomLeft,
-            );
+            let output = self.brush_output(brush);
+
             if !output.is_empty() {
                 match brush.state {
                     // If we're erasing, we can't use the staging framebuffer, since we
-                    // need to be replacing pixels on the real buffer.
-                    _ if brush.is_set(BrushMode::Erase) => {
+                    // need to be replacing pixels on the real buffer. The same goes
+                    // for strokes which are composited by the session.
+                    _ if brush.is_set(BrushMode::Erase) || self.brush_composites(brush) => {
                         self.effects.extend_from_slice(&[
                             Effect::ViewBlendingChanged(Blending::constant()),
                             Effect::ViewPaintFinal(output),
@@ -1064,6 +1357,70 @@ impl Session {
         self.effects()
     }
 
+    /// Return the brush output as shapes. Brush modes which depend on the
+    /// current view pixels, such as alpha-lock, are applied here, since the
+    /// brush itself has no access to them.
+    fn brush_output(&self, brush: &Brush) -> Vec<Shape> {
+        if !self.brush_composites(brush) {
+            return brush.output(
+                Stroke::NONE,
+                Fill::Solid(brush.color.into()),
+                1.0,
+                Origin::BottomLeft,
+            );
+        }
+        let opacity = self.settings["brush/opacity"].float64().clamp(0., 1.) as f32;
+        let blend = self.settings["brush/blend"].blend();
+        let lock = brush.is_set(BrushMode::AlphaLock);
+        let src = brush
+            .color
+            .alpha((brush.color.a as f32 * opacity).round() as u8);
+
+        let resources = self.resources.lock();
+        let (snapshot, pixels) = resources.get_snapshot(self.views.active_id);
+        let (w, h) = (snapshot.width() as i32, snapshot.height() as i32);
+
+        // Every pixel is composited once per stroke, against the pixels of the
+        // view before the stroke started. This keeps the output deterministic,
+        // no matter how many times the stroke passes over a pixel.
+        brush
+            .pixels()
+            .into_iter()
+            .filter(|p| p.x >= 0 && p.y >= 0 && p.x < w && p.y < h)
+            .filter_map(|p| {
+                let dst = pixels.get(((h - p.y - 1) * w + p.x) as usize)?;
+
+                // In alpha-lock mode, only paint over pixels that aren't transparent,
+                // and keep their alpha, so that the silhouette is preserved.
+                if lock && dst.a == 0 {
+                    return None;
+                }
+                let color = if brush.is_set(BrushMode::Erase) {
+                    dst.alpha((dst.a as f32 * (1. - opacity)).round() as u8)
+                } else if lock {
+                    blend.apply(src, dst).alpha(dst.a)
+                } else {
+                    blend.apply(src, dst)
+                };
+                Some(Shape::Rectangle(
+                    Rect::new(p.x as f32, p.y as f32, p.x as f32 + 1., p.y as f32 + 1.),
+                    ZDepth::ZERO,
+                    Rotation::ZERO,
+                    Stroke::NONE,
+                    Fill::Solid(color.into()),
+                ))
+            })
+            .collect()
+    }
+
+    /// Check whether the brush output has to be composited onto the view
+    /// pixels by the session, rather than blended by the renderer.
+    fn brush_composites(&self, brush: &Brush) -> bool {
+        brush.is_set(BrushMode::AlphaLock)
+            || self.settings["brush/opacity"].float64() < 1.
+            || self.settings["brush/blend"].blend() != Blend::Over
+    }
+
     /// Quit the session.
     pub fn quit(&mut self, r: ExitReason) {
         self.transition(State::Closing(r));
@@ -1187,10 +1544,19 @@ impl Session {
             }
             _ => {}
         }
+        self.count = None;
+        self.key_sequence.clear();
+
+        match new {
+            Mode::Command | Mode::Visual(VisualState::Selecting { .. }) => {}
+            _ => self.drop_selection(),
+        }
 
         match new {
             Mode::Normal => {
                 self.selection = None;
+                self.selection_mask = None;
+                self.selection_draft = None;
             }
             Mode::Command => {
                 // When switching to command mode via the keyboard, we simultaneously
@@ -1302,6 +1668,8 @@ impl Session {
         if self.views.active_id == id {
             return;
         }
+        // Lifted pixels belong to the view they were lifted from.
+        self.drop_selection();
         self.views.activate(id);
         self.effects.push(Effect::ViewActivated(id));
     }
@@ -1361,15 +1729,160 @@ impl Session {
         self.view_coords(self.views.active_id, p)
     }
 
+    /// The number of the current frame.
+    pub fn frame_number(&self) -> u64 {
+        self.frame_number
+    }
+
     /// Check whether a point is inside the selection, if any.
     pub fn is_selected(&self, p: ViewCoords<i32>) -> bool {
-        if let Some(s) = self.selection {
+        if let Some(mask) = self.selection_mask() {
+            mask.contains(*p)
+        } else if let Some(s) = self.selection {
             s.abs().bounds().contains(*p)
         } else {
             false
         }
     }
 
+    /// Return the mask of the current selection, if the selection isn't rectangular.
+    /// The mask is discarded as soon as the selection is resized.
+    pub fn selection_mask(&self) -> Option<&Mask> {
+        let s = self.selection?;
+        self.selection_mask
+            .as_ref()
+            .filter(|m| m.rect == s.abs().bounds())
+    }
+
+    /// Return the current selection as a mask, whether it is rectangular or not.
+    fn selection_as_mask(&self) -> Option<Mask> {
+        self.selection.map(|s| {
+            self.selection_mask()
+                .cloned()
+                .unwrap_or_else(|| Mask::from_rect(s.abs().bounds()))
+        })
+    }
+
+    /// Set the current selection from a mask. Masks which select a whole
+    /// rectangle are turned into rectangular selections.
+    fn select_mask(&mut self, mask: Option<Mask>) {
+        let mask = mask.and_then(|m| m.trim());
+
+        self.selection = mask.as_ref().map(|m| Selection::from(m.rect));
+        self.selection_mask = mask.filter(|m| !m.is_full());
+    }
+
+    /// Move the current selection, along with its mask.
+    fn translate_selection(&mut self, x: i32, y: i32) {
+        if let Some(mask) = self.selection_mask.as_mut() {
+            mask.translate(x, y);
+        }
+        if let Some(ref mut s) = self.selection {
+            s.translate(x, y);
+        }
+    }
+
+    /// The way a new selection is combined with the existing one, based on
+    /// the modifier keys held down: `<shift>` adds and `<ctrl>` subtracts.
+    fn selection_op(&self) -> MaskOp {
+        if self.keys_pressed.contains(&platform::Key::Shift) {
+            MaskOp::Add
+        } else if self.keys_pressed.contains(&platform::Key::Control) {
+            MaskOp::Subtract
+        } else {
+            MaskOp::Replace
+        }
+    }
+
+    /// Start a selection draft at the given point.
+    fn start_selection(&mut self, p: Point2<i32>, op: MaskOp) {
+        let base = self.selection_as_mask();
+
+        match self.settings["selection/tool"].selection_tool() {
+            SelectionTool::Rect => {
+                let s = Selection::new(p.x, p.y, p.x + 1, p.y + 1);
+
+                self.selection = Some(self.snap_selection(s, p));
+                self.selection_mask = None;
+
+                if op != MaskOp::Replace {
+                    self.selection_draft = Some(SelectionDraft {
+                        op,
+                        base,
+                        lasso: Vec::new(),
+                    });
+                }
+            }
+            SelectionTool::Lasso => {
+                if op == MaskOp::Replace {
+                    self.selection = None;
+                }
+                self.selection_draft = Some(SelectionDraft {
+                    op,
+                    base,
+                    lasso: vec![p],
+                });
+            }
+            SelectionTool::Wand => {
+                let wand = self.wand(p);
+                self.select_mask(Self::combine_masks(base, wand, op));
+            }
+        }
+    }
+
+    /// Finish the selection draft, if any, by combining it with the selection
+    /// it was started from.
+    fn finish_selection(&mut self) {
+        if let Some(draft) = self.selection_draft.take() {
+            let new = if draft.lasso.is_empty() {
+                self.selection.map(|s| Mask::from_rect(s.abs().bounds()))
+            } else {
+                Mask::polygon(&draft.lasso)
+            };
+            self.select_mask(Self::combine_masks(draft.base, new, draft.op));
+        }
+    }
+
+    fn combine_masks(base: Option<Mask>, new: Option<Mask>, op: MaskOp) -> Option<Mask> {
+        match (base, new) {
+            (Some(base), Some(new)) => Some(base.combine(&new, op)),
+            (None, new) if op != MaskOp::Subtract => new,
+            (base, _) => base,
+        }
+    }
+
+    /// Select the pixels of the active view which have the same color as the
+    /// given pixel, within the configured tolerance.
+    fn wand(&self, p: Point2<i32>) -> Option<Mask> {
+        let v = self.active_view();
+        let bounds = v.bounds();
+
+        if !bounds.contains(p) {
+            return None;
+        }
+        let pixels = self.resources.lock().get_snapshot_rect(v.id, &bounds);
+        let w = bounds.width() as usize;
+        let (x, y) = (p.x as usize, (bounds.y2 - p.y - 1) as usize);
+        let color = pixels[y * w + x];
+        let tolerance = self.settings["selection/tolerance"].uint64() as i32;
+
+        let matches = |c: Rgba8| {
+            (c.r as i32 - color.r as i32).abs() <= tolerance
+                && (c.g as i32 - color.g as i32).abs() <= tolerance
+                && (c.b as i32 - color.b as i32).abs() <= tolerance
+                && (c.a as i32 - color.a as i32).abs() <= tolerance
+        };
+        let selected = if self.settings["selection/contiguous"].is_set() {
+            util::flood_fill(&pixels, w, x, y, matches)
+        } else {
+            pixels.iter().map(|c| matches(*c)).collect()
+        };
+
+        Some(Mask::from_fn(bounds, |p| {
+            selected[(bounds.y2 - p.y - 1) as usize * w + p.x as usize]
+        }))
+    }
+
     /// Edit paths.
     ///
     /// Loads the given files into the session. Returns an error if one of
@@ -1638,129 +2151,744 @@ impl Session {
         }
     }
 
-    /// Yank the selection.
-    fn yank_selection(&mut self) -> Option<Rect<i32>> {
-        if let (Mode::Visual(VisualState::Selecting { .. }), Some(s)) = (self.mode, self.selection)
-        {
-            let v = self.active_view_mut();
-            let s = s.abs().bounds();
-
-            if s.intersects(v.bounds()) {
-                let s = s.intersection(v.bounds());
+    /// Replace the pixels selected by the mask, as a function of their current color.
+    fn paint_mask<F>(&mut self, mask: &Mask, f: F)
+    where
+        F: Fn(Rgba8) -> Rgba8,
+    {
+        if let Some((area, pixels)) = self.masked_pixels(mask, f) {
+            self.active_view_mut().set_pixels(area, pixels);
+        }
+    }
 
-                v.yank(s);
+    /// Pixels of the area covered by the mask, with the selected pixels replaced as
+    /// a function of their current color. Returns `None` if the area is empty.
+    fn masked_pixels<F>(&self, mask: &Mask, f: F) -> Option<(Rect<i32>, Vec<Rgba8>)>
+    where
+        F: Fn(Rgba8) -> Rgba8,
+    {
+        let area = mask.rect.intersection(self.active_view().bounds());
+        if area.is_empty() {
+            return None;
+        }
+        let mut pixels = self
+            .resources
+            .lock()
+            .get_snapshot_rect(self.views.active_id, &area);
+        let w = area.width();
 
-                self.selection = Some(Selection::from(s));
-                self.switch_mode(Mode::Visual(VisualState::Pasting));
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let p = Point2::new(area.x1 + i as i32 % w, area.y2 - i as i32 / w - 1);
 
-                return Some(s);
+            if mask.contains(p) {
+                *pixel = f(*pixel);
             }
         }
-        None
-    }
-
-    fn undo(&mut self, id: ViewId) {
-        self.restore_view_snapshot(id, Direction::Backward);
+        Some((area, pixels))
     }
 
-    fn redo(&mut self, id: ViewId) {
-        self.restore_view_snapshot(id, Direction::Forward);
+    /// Area covered by the result of transforming the pixels of `r`, given its new size.
+    fn transformed_rect(r: Rect<i32>, w: u32, h: u32, centered: bool) -> Rect<i32> {
+        let (x, y) = if centered {
+            (
+                r.x1 + (r.width() - w as i32).div_euclid(2),
+                r.y1 + (r.height() - h as i32).div_euclid(2),
+            )
+        } else {
+            (r.x1, r.y1)
+        };
+        Rect::new(x, y, x + w as i32, y + h as i32)
     }
 
-    fn restore_view_snapshot(&mut self, id: ViewId, dir: Direction) {
-        let snapshot = self
-            .resources
-            .lock_mut()
-            .get_view_mut(id)
-            .and_then(|s| {
-                if dir == Direction::Backward {
-                    s.prev_snapshot()
-                } else {
-                    s.next_snapshot()
-                }
-            })
-            .map(|s| (s.id, s.extent));
-
-        if let Some((sid, extent)) = snapshot {
-            let v = self.view_mut(id);
+    /// Transform the selected pixels in place, or the paste buffer while pasting.
+    /// The selection is resized to fit the transformed pixels, keeping either its center
+    /// or its bottom-left corner in place. In-place transforms are a single edit of the view.
+    fn transform_selection<F>(&mut self, f: F, centered: bool)
+    where
+        F: Fn(&Bitmap) -> Bitmap,
+    {
+        let s = match self.selection {
+            Some(s) => s.abs().bounds(),
+            None => return,
+        };
 
-            v.reset(extent);
-            v.damaged();
+        match self.mode {
+            _ if self.floating || self.mode == Mode::Visual(VisualState::Pasting) => {
+                let paste = match self.resources.lock().paste.as_ref().map(&f) {
+                    Some(paste) => paste,
+                    None => return,
+                };
+                // Lifted pixels may have a mask, which is transformed with them.
+                let selected = self.selection_mask().map(|m| f(&Self::mask_bitmap(m, s)));
+                let dst = Self::transformed_rect(s, paste.w, paste.h, centered);
+
+                self.selection = Some(Selection::from(dst));
+                self.selection_mask = selected.map(|selected| Self::bitmap_mask(&selected, dst));
+                self.resources.lock_mut().paste = Some(paste);
+                self.rotation = None;
+                self.effects.push(Effect::PasteBufferChanged);
+            }
+            Mode::Visual(VisualState::Selecting { .. }) => {
+                let v = self.active_view();
+                let bounds = v.bounds();
+                let src = s.intersection(bounds);
 
-            // If the snapshot was saved to disk, we mark the view as saved too.
-            // Otherwise, if the view was saved before restoring the snapshot,
-            // we mark it as modified.
-            match v.file_status {
-                FileStatus::Modified(ref f) if v.is_snapshot_saved(sid) => {
-                    v.file_status = FileStatus::Saved(f.clone());
-                }
-                FileStatus::Saved(ref f) => {
-                    v.file_status = FileStatus::Modified(f.clone());
+                if src.is_empty() {
+                    return;
                 }
-                _ => {
-                    // TODO
+                let mask = self.selection_mask().cloned();
+                let is_selected = |p: Point2<i32>| mask.as_ref().map_or(true, |m| m.contains(p));
+                let (w, h) = (src.width() as u32, src.height() as u32);
+
+                // The selection itself is transformed along with the pixels, so that
+                // the transformed mask is known.
+                let pixels = Bitmap::new(w, h, self.resources.lock().get_snapshot_rect(v.id, &src));
+                let selected = Bitmap::from_fn(w, h, |x, y| {
+                    if is_selected(Point2::new(src.x1 + x as i32, src.y2 - y as i32 - 1)) {
+                        color::WHITE
+                    } else {
+                        Rgba8::TRANSPARENT
+                    }
+                });
+                let (pixels, selected) = (f(&pixels), f(&selected));
+
+                let dst = Self::transformed_rect(src, pixels.w, pixels.h, centered);
+                let area = Rect::new(
+                    src.x1.min(dst.x1),
+                    src.y1.min(dst.y1),
+                    src.x2.max(dst.x2),
+                    src.y2.max(dst.y2),
+                )
+                .intersection(bounds);
+                let is_transformed =
+                    |p| Bitmap::coords(dst, p).is_some_and(|(x, y)| selected.get(x, y).a > 0);
+
+                let mut out = Bitmap::new(
+                    area.width() as u32,
+                    area.height() as u32,
+                    self.resources.lock().get_snapshot_rect(v.id, &area),
+                );
+                for y in area.y1..area.y2 {
+                    for x in area.x1..area.x2 {
+                        let p = Point2::new(x, y);
+                        let (ox, oy) = (x - area.x1, area.y2 - y - 1);
+
+                        if is_transformed(p) {
+                            if let Some((dx, dy)) = Bitmap::coords(dst, p) {
+                                out.set(ox as u32, oy as u32, pixels.get(dx, dy));
+                            }
+                        } else if src.contains(p) && is_selected(p) {
+                            out.set(ox as u32, oy as u32, Rgba8::TRANSPARENT);
+                        }
+                    }
                 }
+                let mask = mask.map(|_| Mask::from_fn(dst, is_transformed));
+
+                self.active_view_mut().set_pixels(area, out.pixels);
+                self.selection = Some(Selection::from(dst));
+                self.selection_mask = mask;
             }
-            self.cursor_dirty();
+            _ => {}
         }
     }
 
-    ///////////////////////////////////////////////////////////////////////////
-    // Internal command handler
-    ///////////////////////////////////////////////////////////////////////////
-
-    fn handle_internal_cmd(&mut self, cmd: InternalCommand, exec: &mut Execution) {
-        match cmd {
-            InternalCommand::StopRecording => match exec.stop_recording() {
-                Ok(path) => {
-                    self.message(
-                        format!("Recording saved to `{}`", path.display()),
-                        MessageType::Replay,
-                    );
-                    info!("recording: events saved to `{}`", path.display());
-                    self.quit(ExitReason::Normal);
-                }
-                Err(e) => {
-                    error!("recording: error stopping: {}", e);
-                }
+    /// Rotate the selection by an arbitrary angle, around its center. While pasting,
+    /// the paste buffer is rotated from its unrotated pixels, so that rotating it
+    /// repeatedly doesn't blur or grow it.
+    fn rotate_selection(&mut self, degrees: f32) {
+        let s = match self.selection {
+            Some(s) => s.abs().bounds(),
+            None => return,
+        };
+        if !self.floating && self.mode != Mode::Visual(VisualState::Pasting) {
+            if self.check_rotation_size(s.width() as u32, s.height() as u32) {
+                self.transform_selection(|b| b.rotsprite(degrees), true);
+            }
+            return;
+        }
+        let rotation = match self.rotation.take() {
+            Some(rotation) => rotation,
+            None => match self.resources.lock().paste.clone() {
+                Some(pixels) => PasteRotation {
+                    pixels,
+                    selected: self.selection_mask().map(|m| Self::mask_bitmap(m, s)),
+                    degrees: 0.,
+                },
+                None => return,
             },
+        };
+        if !self.check_rotation_size(rotation.pixels.w, rotation.pixels.h) {
+            self.rotation = Some(rotation);
+            return;
         }
+        let degrees = (rotation.degrees + degrees).rem_euclid(360.);
+        let paste = rotation.pixels.rotsprite(degrees);
+        let selected = rotation.selected.as_ref().map(|b| b.rotsprite(degrees));
+        let dst = Self::transformed_rect(s, paste.w, paste.h, true);
+
+        self.selection = Some(Selection::from(dst));
+        self.selection_mask = selected.map(|selected| Self::bitmap_mask(&selected, dst));
+        self.resources.lock_mut().paste = Some(paste);
+        self.rotation = Some(PasteRotation {
+            degrees,
+            ..rotation
+        });
+        self.effects.push(Effect::PasteBufferChanged);
     }
 
-    ///////////////////////////////////////////////////////////////////////////
-    // Event handlers
-    ///////////////////////////////////////////////////////////////////////////
+    /// Check that pixels of the given size can be rotated by an arbitrary angle.
+    /// Reports an error and returns `false` if they can't.
+    fn check_rotation_size(&mut self, w: u32, h: u32) -> bool {
+        // Rotation works on an upscaled copy of the pixels.
+        let max = Self::MAX_FRAME_SIZE / Bitmap::ROTSPRITE_UPSCALE;
 
-    pub fn handle_event(&mut self, event: Event, exec: &mut Execution) {
-        if let Execution::Recording {
-            ref mut events,
-            start,
-            ..
-        } = exec
-        {
-            events.push(TimedEvent::new(
-                self.frame_number,
-                start.elapsed(),
-                event.clone(),
-            ));
+        if w > max || h > max {
+            self.message(
+                format!("Error: maximum size for rotation is {}x{}", max, max),
+                MessageType::Error,
+            );
+            return false;
         }
+        true
+    }
 
-        match event {
-            Event::MouseInput(btn, st) => {
-                if self.settings["input/mouse"].is_set() {
-                    self.handle_mouse_input(btn, st);
-                }
-            }
-            Event::MouseWheel(delta) => {
-                if self.settings["input/mouse"].is_set() {
-                    self.handle_mouse_wheel(delta);
-                }
+    /// Convert a mask to a bitmap covering the given area, in which selected
+    /// pixels are opaque, so that it can be transformed like pixels.
+    fn mask_bitmap(mask: &Mask, area: Rect<i32>) -> Bitmap {
+        Bitmap::from_fn(area.width() as u32, area.height() as u32, |x, y| {
+            if mask.contains(Point2::new(area.x1 + x as i32, area.y2 - y as i32 - 1)) {
+                color::WHITE
+            } else {
+                Rgba8::TRANSPARENT
             }
-            Event::CursorMoved(position) => {
-                if self.settings["input/mouse"].is_set() {
-                    let coords = self.window_to_session_coords(position);
-                    self.handle_cursor_moved(coords);
-                }
+        })
+    }
+
+    /// Convert a bitmap covering the given area back to a mask, see `mask_bitmap`.
+    fn bitmap_mask(bitmap: &Bitmap, area: Rect<i32>) -> Mask {
+        Mask::from_fn(area, |p| {
+            Bitmap::coords(area, p).is_some_and(|(x, y)| bitmap.get(x, y).a > 0)
+        })
+    }
+
+    /// Scale the active view. Each frame is scaled separately, so that frames stay
+    /// aligned, even when the scaled frame size has to be rounded.
+    fn scale_view(&mut self, sx: f32, sy: f32) {
+        let epx = self.settings["scale/epx"].is_set();
+        let v = self.active_view();
+        let (fw, fh) = (v.fw, v.fh);
+
+        // Check the scaled size before scaling anything, since large factors
+        // would otherwise allocate huge frames.
+        if !self.check_scaled_size(fw, fh, sx, sy, "frame") {
+            return;
+        }
+        let v = self.active_view();
+        let frames = (0..v.animation.len() as u32)
+            .map(|i| {
+                let frame = Rect::new(i * fw, 0, (i + 1) * fw, fh).map(|n| n as i32);
+                let pixels = self.resources.lock().get_snapshot_rect(v.id, &frame);
+
+                Bitmap::new(fw, fh, pixels).scale(sx, sy, epx)
+            })
+            .collect::<Vec<_>>();
+        let (fw, fh) = (frames[0].w, frames[0].h);
+        let mut out = Bitmap::filled(fw * frames.len() as u32, fh, Rgba8::TRANSPARENT);
+        for (i, frame) in frames.iter().enumerate() {
+            out.blit(frame, i as u32 * fw, 0);
+        }
+
+        let v = self.active_view_mut();
+        v.resize_frames(fw, fh);
+        v.set_pixels(Rect::origin(out.w as i32, out.h as i32), out.pixels);
+
+        self.check_selection();
+        self.organize_views();
+    }
+
+    /// Check that scaling something of the given size by the given factors doesn't
+    /// exceed the maximum frame size. Reports an error and returns `false` if it does.
+    fn check_scaled_size(&mut self, w: u32, h: u32, sx: f32, sy: f32, what: &str) -> bool {
+        let (w, h) = Bitmap::scaled_size(w, h, sx, sy);
+
+        if w > Self::MAX_FRAME_SIZE || h > Self::MAX_FRAME_SIZE {
+            self.message(
+                format!(
+                    "Error: maximum {} size is {}x{}",
+                    what,
+                    Self::MAX_FRAME_SIZE,
+                    Self::MAX_FRAME_SIZE,
+                ),
+                MessageType::Error,
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Get the selected pixels of the active view, along with the area they cover.
+    /// Pixels outside of the selection mask are transparent.
+    fn selected_pixels(&self) -> Option<(Rect<i32>, Option<Mask>, Bitmap)> {
+        let s = self.selection?.abs().bounds();
+        let mask = self.selection_mask().cloned();
+        let v = self.active_view();
+
+        if !s.intersects(v.bounds()) {
+            return None;
+        }
+        let s = s.intersection(v.bounds());
+        let mask = mask.map(|m| Mask::from_fn(s, |p| m.contains(p)));
+        let mut pixels = self.resources.lock().get_snapshot_rect(v.id, &s);
+
+        if let Some(mask) = &mask {
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                let (x, y) = (i as i32 % s.width(), i as i32 / s.width());
+
+                if !mask.contains(Point2::new(s.x1 + x, s.y2 - y - 1)) {
+                    *pixel = Rgba8::TRANSPARENT;
+                }
+            }
+        }
+        Some((
+            s,
+            mask,
+            Bitmap::new(s.width() as u32, s.height() as u32, pixels),
+        ))
+    }
+
+    /// Write the selected pixels to a PNG file.
+    fn save_selection_as<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+
+        if path.extension().map_or(true, |ext| ext != "png") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file path requires a .png extension",
+            ));
+        }
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("\"{}\" already exists", path.display()),
+            ));
+        }
+        let (_, _, selected) = self
+            .selected_pixels()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "nothing selected"))?;
+
+        ResourceManager::save_image(path, selected.w, selected.h, &selected.pixels)?;
+
+        self.message(
+            format!(
+                "\"{}\" {} pixels written",
+                path.display(),
+                selected.pixels.len()
+            ),
+            MessageType::Info,
+        );
+        Ok(())
+    }
+
+    /// Open the selected pixels in a new view.
+    fn selection_to_view(&mut self) {
+        let (_, _, selected) = match self.selected_pixels() {
+            Some(selected) => selected,
+            None => return,
+        };
+        let id = self.views.add(FileStatus::NoFile, selected.w, selected.h);
+
+        self.effects.push(Effect::ViewAdded(id));
+        self.resources.add_view(
+            id,
+            selected.w,
+            selected.h,
+            Pixels::Rgba(selected.pixels.into()),
+        );
+        self.organize_views();
+        self.edit_view(id);
+    }
+
+    /// Yank the selection into the paste buffer, and optionally into a register.
+    fn yank_selection(&mut self, register: Option<char>) -> Option<Rect<i32>> {
+        if let (true, Some(s)) = (self.floating, self.selection) {
+            // The lifted pixels are already in the paste buffer.
+            if let Some(r) = register {
+                let mut resources = self.resources.lock_mut();
+                if let Some(paste) = resources.paste.clone() {
+                    resources.registers.insert(r, paste);
+                }
+            }
+            self.drop_selection();
+            self.switch_mode(Mode::Visual(VisualState::Pasting));
+
+            return Some(s.abs().bounds());
+        }
+        if let Mode::Visual(VisualState::Selecting { .. }) = self.mode {
+            if let Some((s, mask, yanked)) = self.selected_pixels() {
+                let mut resources = self.resources.lock_mut();
+
+                if let Some(r) = register {
+                    resources.registers.insert(r, yanked.clone());
+                }
+                resources.paste = Some(yanked);
+                drop(resources);
+
+                self.rotation = None;
+                self.effects.push(Effect::PasteBufferChanged);
+
+                self.selection = Some(Selection::from(s));
+                self.selection_mask = mask;
+                self.switch_mode(Mode::Visual(VisualState::Pasting));
+
+                return Some(s);
+            }
+        }
+        None
+    }
+
+    /// Paste the paste buffer onto the given area of the active view. Unless one of
+    /// the `paste/*` settings is set, the paste buffer is copied over as-is.
+    fn paste_selection(&mut self, area: Rect<i32>) {
+        let transparent = self.settings["paste/transparent"].is_set();
+        let behind = self.settings["paste/behind"].is_set();
+        let colorkey = self.settings["paste/colorkey"].is_set();
+
+        if !transparent && !behind && !colorkey {
+            self.active_view_mut().paste(area);
+            return;
+        }
+        let bg = self.bg;
+
+        self.paste_pixels(area, |_, src, dst| {
+            !((transparent && src.a == 0) || (behind && dst.a != 0) || (colorkey && src == bg))
+        });
+    }
+
+    /// Copy the paste buffer onto the given area of the active view, pixel by pixel.
+    /// Only the pixels for which `keep` returns `true`, given their position and
+    /// the source and destination colors, are copied.
+    fn paste_pixels<F>(&mut self, area: Rect<i32>, keep: F)
+    where
+        F: Fn(Point2<i32>, Rgba8, Rgba8) -> bool,
+    {
+        let v = self.active_view();
+        let dst = area.intersection(v.bounds());
+
+        if dst.is_empty() {
+            return;
+        }
+        let mut pixels = Bitmap::new(
+            dst.width() as u32,
+            dst.height() as u32,
+            self.resources.lock().get_snapshot_rect(v.id, &dst),
+        );
+        // Pixels that were lifted off the view aren't part of the snapshot yet.
+        if let Some(hole) = &self.lifted {
+            let w = dst.width();
+
+            for (i, pixel) in pixels.pixels.iter_mut().enumerate() {
+                let p = Point2::new(dst.x1 + i as i32 % w, dst.y2 - i as i32 / w - 1);
+
+                if hole.contains(p) {
+                    *pixel = Rgba8::TRANSPARENT;
+                }
+            }
+        }
+        {
+            let resources = self.resources.lock();
+            let paste = match &resources.paste {
+                Some(paste) => paste,
+                None => return,
+            };
+            for y in dst.y1..dst.y2 {
+                for x in dst.x1..dst.x2 {
+                    let p = Point2::new(x, y);
+                    let (Some((sx, sy)), Some((dx, dy))) =
+                        (Bitmap::coords(area, p), Bitmap::coords(dst, p))
+                    else {
+                        continue;
+                    };
+                    if sx >= paste.w || sy >= paste.h {
+                        continue;
+                    }
+                    let src = paste.get(sx, sy);
+
+                    if keep(p, src, pixels.get(dx, dy)) {
+                        pixels.set(dx, dy, src);
+                    }
+                }
+            }
+        }
+        self.active_view_mut().set_pixels(dst, pixels.pixels);
+    }
+
+    /// Outline the selection, or every frame of the active view if there is no
+    /// selection. Outlines never cross frame boundaries.
+    fn outline(&mut self, color: Rgba8) {
+        let diagonal = self.settings["outline/diagonal"].is_set();
+        let inside = self.settings["outline/inside"].is_set();
+        let v = self.active_view();
+        let extent = v.extent();
+        let bounds = v.bounds();
+
+        let (area, mask) = match self.selection {
+            Some(s) => (
+                s.abs().bounds().intersection(bounds),
+                self.selection_mask().cloned(),
+            ),
+            None => (bounds, None),
+        };
+        if area.is_empty() {
+            return;
+        }
+        let mut out = Bitmap::new(
+            area.width() as u32,
+            area.height() as u32,
+            self.resources.lock().get_snapshot_rect(v.id, &area),
+        );
+
+        for i in 0..extent.nframes as u32 {
+            let frame = Rect::new(i * extent.fw, 0, (i + 1) * extent.fw, extent.fh)
+                .map(|n| n as i32)
+                .intersection(area);
+
+            if frame.is_empty() {
+                continue;
+            }
+            let outlined = Bitmap::new(
+                frame.width() as u32,
+                frame.height() as u32,
+                self.resources.lock().get_snapshot_rect(v.id, &frame),
+            )
+            .outline(color, diagonal, inside);
+
+            for y in frame.y1..frame.y2 {
+                for x in frame.x1..frame.x2 {
+                    let p = Point2::new(x, y);
+
+                    if mask.as_ref().is_some_and(|m| !m.contains(p)) {
+                        continue;
+                    }
+                    if let (Some((ox, oy)), Some((fx, fy))) =
+                        (Bitmap::coords(area, p), Bitmap::coords(frame, p))
+                    {
+                        out.set(ox, oy, outlined.get(fx, fy));
+                    }
+                }
+            }
+        }
+        self.active_view_mut().set_pixels(area, out.pixels);
+    }
+
+    /// Lift the selected pixels off the view, so that they move along with the
+    /// selection. Lifted pixels are held in the paste buffer until they are dropped.
+    fn lift_selection(&mut self) {
+        if self.floating || !self.settings["selection/float"].is_set() {
+            return;
+        }
+        if !matches!(self.mode, Mode::Visual(VisualState::Selecting { .. })) {
+            return;
+        }
+        let (s, mask, lifted) = match self.selected_pixels() {
+            Some(selected) => selected,
+            None => return,
+        };
+        self.resources.lock_mut().paste = Some(lifted);
+        self.rotation = None;
+        self.effects.push(Effect::PasteBufferChanged);
+
+        // The hole left by the lifted pixels is only drawn for now. It's stored
+        // along with the dropped pixels, so that the move is a single edit.
+        let hole = mask.clone().unwrap_or_else(|| Mask::from_rect(s));
+        if let Some((area, pixels)) = self.masked_pixels(&hole, |_| Rgba8::TRANSPARENT) {
+            self.active_view_mut().draw_pixels(area, pixels);
+        }
+
+        self.selection = Some(Selection::from(s));
+        self.selection_mask = mask;
+        self.lifted = Some(hole);
+        self.floating = true;
+    }
+
+    /// Drop the lifted pixels onto the view, where the selection is.
+    fn drop_selection(&mut self) {
+        if !self.floating {
+            return;
+        }
+        self.floating = false;
+
+        if let Some(hole) = self.lifted.clone() {
+            self.paint_mask(&hole, |_| Rgba8::TRANSPARENT);
+        }
+        if let Some(s) = self.selection {
+            let mask = self.selection_mask().cloned();
+
+            self.paste_pixels(s.abs().bounds(), |p, _, _| {
+                mask.as_ref().map_or(true, |m| m.contains(p))
+            });
+        }
+        self.lifted = None;
+    }
+
+    /// Put the lifted pixels back where they were, without changing the view.
+    fn cancel_float(&mut self) {
+        if !self.floating {
+            return;
+        }
+        self.floating = false;
+
+        if let Some(hole) = self.lifted.take() {
+            self.selection = Some(Selection::from(hole.rect));
+            self.selection_mask = Some(hole);
+        }
+        self.active_view_mut().damaged();
+    }
+
+    /// Load a register into the paste buffer, and start pasting it at the cursor.
+    fn paste_register(&mut self, register: char) {
+        let paste = self.resources.lock().registers.get(&register).cloned();
+        let paste = match paste {
+            Some(b) => b,
+            None => {
+                self.message(
+                    format!("Error: register {} is empty", register),
+                    MessageType::Error,
+                );
+                return;
+            }
+        };
+        let (w, h) = (paste.w as i32, paste.h as i32);
+
+        self.resources.lock_mut().paste = Some(paste);
+        self.rotation = None;
+        self.effects.push(Effect::PasteBufferChanged);
+
+        self.selection = Some(Selection::new(0, 0, w, h));
+        self.selection_mask = None;
+        self.switch_mode(Mode::Visual(VisualState::Pasting));
+        self.center_selection(self.cursor);
+    }
+
+    fn undo(&mut self, id: ViewId) {
+        // Undoing while moving pixels around only cancels the move, since it
+        // isn't part of the view's snapshots yet.
+        if self.floating && id == self.views.active_id {
+            self.cancel_float();
+            return;
+        }
+        self.restore_view_snapshot(id, Direction::Backward);
+    }
+
+    fn redo(&mut self, id: ViewId) {
+        if id == self.views.active_id {
+            self.cancel_float();
+        }
+        self.restore_view_snapshot(id, Direction::Forward);
+    }
+
+    fn restore_view_snapshot(&mut self, id: ViewId, dir: Direction) {
+        let snapshot = self
+            .resources
+            .lock_mut()
+            .get_view_mut(id)
+            .and_then(|s| {
+                if dir == Direction::Backward {
+                    s.prev_snapshot()
+                } else {
+                    s.next_snapshot()
+                }
+            })
+            .map(|s| (s.id, s.extent));
+
+        if let Some((sid, extent)) = snapshot {
+            let v = self.view_mut(id);
+
+            v.reset(extent);
+            v.damaged();
+
+            // If the snapshot was saved to disk, we mark the view as saved too.
+            // Otherwise, if the view was saved before restoring the snapshot,
+            // we mark it as modified.
+            match v.file_status {
+                FileStatus::Modified(ref f) if v.is_snapshot_saved(sid) => {
+                    v.file_status = FileStatus::Saved(f.clone());
+                }
+                FileStatus::Saved(ref f) => {
+                    v.file_status = FileStatus::Modified(f.clone());
+                }
+                _ => {
+                    // TODO
+                }
+            }
+            self.cursor_dirty();
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Internal command handler
+    ///////////////////////////////////////////////////////////////////////////
+
+    fn handle_internal_cmd(&mut self, cmd: InternalCommand, exec: &mut Execution) {
+        match cmd {
+            InternalCommand::StopRecording => match exec.stop_recording() {
+                Ok(path) => {
+                    self.message(
+                        format!("Recording saved to `{}`", path.display()),
+                        MessageType::Replay,
+                    );
+                    info!("recording: events saved to `{}`", path.display());
+                    self.quit(ExitReason::Normal);
+                }
+                Err(e) => {
+                    error!("recording: error stopping: {}", e);
+                }
+            },
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Event handlers
+    ///////////////////////////////////////////////////////////////////////////
+
+    pub fn handle_event(&mut self, event: Event, exec: &mut Execution) {
+        if let Execution::Recording {
+            ref mut events,
+            start,
+            ..
+        } = exec
+        {
+            // Keys that aren't known can't be replayed, so they aren't recorded.
+            let known = match event {
+                Event::KeyboardInput(KeyboardInput { key, .. }) => {
+                    key.is_some_and(|k| k != platform::Key::Unknown)
+                }
+                _ => true,
+            };
+            if known {
+                events.push(TimedEvent::new(
+                    self.frame_number,
+                    start.elapsed(),
+                    event.clone(),
+                ));
+            }
+        }
+
+        match event {
+            Event::MouseInput(btn, st) => {
+                if self.settings["input/mouse"].is_set() {
+                    self.handle_mouse_input(btn, st);
+                }
+            }
+            Event::MouseWheel(delta) => {
+                if self.settings["input/mouse"].is_set() {
+                    self.handle_mouse_wheel(delta);
+                }
+            }
+            Event::CursorMoved(position) => {
+                if self.settings["input/mouse"].is_set() {
+                    let coords = self.window_to_session_coords(position);
+                    self.handle_cursor_moved(coords);
+                }
             }
             Event::KeyboardInput(input) => self.handle_keyboard_input(input, exec),
             Event::ReceivedCharacter(c) => self.handle_received_character(c),
@@ -1835,6 +2963,8 @@ impl Session {
                         let v = self.active_view();
                         let p = self.view_coords(v.id, self.cursor);
                         let extent = v.extent();
+                        let op = self.selection_op();
+                        let selected = self.is_selected(ViewCoords::new(p.x as i32, p.y as i32));
 
                         match self.mode {
                             Mode::Normal => match self.tool {
@@ -1845,10 +2975,17 @@ impl Session {
                                         self.fg
                                     };
                                     brush.start_drawing(p.into(), color, extent);
+
+                                    if self.settings["brush/smoothing"].uint64() > 0 {
+                                        self.stabilized_cursor = Some(self.cursor);
+                                    }
                                 }
                                 Tool::Sampler => {
                                     self.sample_color();
                                 }
+                                Tool::Gradient(ref mut state) => {
+                                    *state = GradientState::Dragging(p.into());
+                                }
                                 Tool::Pan(_) => {}
                             },
                             Mode::Command => {
@@ -1856,20 +2993,24 @@ impl Session {
                             }
                             Mode::Visual(VisualState::Selecting { ref mut dragging }) => {
                                 let p = p.map(|n| n as i32);
-                                let unit = Selection::new(p.x, p.y, p.x + 1, p.y + 1);
 
-                                if let Some(s) = &mut self.selection {
-                                    if s.abs().bounds().contains(p) {
-                                        *dragging = true;
-                                    } else {
-                                        self.selection = Some(unit);
-                                    }
+                                // Clicking inside the selection without modifiers moves it,
+                                // or fills it with a gradient when using the gradient tool.
+                                // Otherwise a new selection is started.
+                                if let (true, Tool::Gradient(ref mut state)) =
+                                    (selected, &mut self.tool)
+                                {
+                                    *state = GradientState::Dragging(ViewCoords::new(p.x, p.y));
+                                } else if op == MaskOp::Replace && selected {
+                                    *dragging = true;
+                                    self.lift_selection();
                                 } else {
-                                    self.selection = Some(unit);
+                                    self.drop_selection();
+                                    self.start_selection(p, op);
                                 }
                             }
                             Mode::Visual(VisualState::Pasting) => {
-                                self.command(Command::SelectionPaste);
+                                self.run(Command::SelectionPaste(None));
                             }
                             Mode::Present | Mode::Help => {}
                         }
@@ -1881,8 +3022,10 @@ impl Session {
                     // Clicking outside a view...
                     match self.mode {
                         Mode::Visual(VisualState::Selecting { ref mut dragging }) => {
-                            self.selection = None;
                             *dragging = false;
+                            self.drop_selection();
+                            self.selection = None;
+                            self.selection_mask = None;
                         }
                         _ => {}
                     }
@@ -1891,16 +3034,31 @@ impl Session {
             InputState::Released => match self.mode {
                 Mode::Visual(VisualState::Selecting { ref mut dragging }) => {
                     *dragging = false;
+
+                    if let Tool::Gradient(GradientState::Dragging(from)) = self.tool {
+                        let to = self.active_view_coords(self.cursor).into();
+
+                        self.tool = Tool::Gradient(GradientState::NotDragging);
+                        self.gradient(from, to);
+                    } else {
+                        self.finish_selection();
+                    }
                 }
                 Mode::Normal => {
                     if let Tool::Brush(ref mut brush) = self.tool {
                         match brush.state {
                             BrushState::Drawing { .. } | BrushState::DrawStarted { .. } => {
                                 brush.stop_drawing();
+                                self.stabilized_cursor = None;
                                 self.active_view_mut().touch();
                             }
                             _ => {}
                         }
+                    } else if let Tool::Gradient(GradientState::Dragging(from)) = self.tool {
+                        let to = self.active_view_coords(self.cursor).into();
+
+                        self.tool = Tool::Gradient(GradientState::NotDragging);
+                        self.gradient(from, to);
                     }
                 }
                 _ => {}
@@ -1925,10 +3083,21 @@ impl Session {
             return;
         }
 
-        let p = self.active_view_coords(cursor);
-        let prev_p = self.active_view_coords(self.cursor);
+        let mut p = self.active_view_coords(cursor);
+        let mut prev_p = self.active_view_coords(self.cursor);
         let (vw, vh) = self.active_view().size();
 
+        // When the stroke stabilizer is active, the brush follows the
+        // stabilized cursor instead of the real one.
+        if let Some(prev) = self.stabilized_cursor {
+            let length = self.settings["brush/smoothing"].uint64() as f32;
+            let next = Self::stabilize(prev, cursor, length);
+
+            self.stabilized_cursor = Some(next);
+            p = self.active_view_coords(next);
+            prev_p = self.active_view_coords(prev);
+        }
+
         match self.tool {
             Tool::Pan(PanState::Panning) => {
                 self.pan(cursor.x - self.cursor.x, cursor.y - self.cursor.y);
@@ -1960,8 +3129,23 @@ impl Session {
                     },
                     Mode::Visual(VisualState::Selecting { dragging: false }) => {
                         if self.mouse_state == InputState::Pressed {
-                            if let Some(ref mut s) = self.selection {
-                                *s = Selection::new(s.x1, s.y1, p.x as i32 + 1, p.y as i32 + 1);
+                            let p = p.map(|n| n as i32);
+
+                            match self.settings["selection/tool"].selection_tool() {
+                                SelectionTool::Rect => {
+                                    if let Some(s) = self.selection {
+                                        let s = Selection::new(s.x1, s.y1, p.x + 1, p.y + 1);
+                                        self.selection = Some(self.snap_selection(s, p));
+                                    }
+                                }
+                                SelectionTool::Lasso => {
+                                    if let Some(ref mut draft) = self.selection_draft {
+                                        if draft.lasso.last() != Some(&p) {
+                                            draft.lasso.push(p);
+                                        }
+                                    }
+                                }
+                                SelectionTool::Wand => {}
                             }
                         }
                     }
@@ -1969,14 +3153,25 @@ impl Session {
                         let view = self.active_view().bounds();
 
                         if self.mouse_state == InputState::Pressed && p != prev_p {
-                            if let Some(ref mut s) = self.selection {
+                            if let Some(s) = self.selection {
                                 // TODO: (rgx) Better API.
                                 let delta = *p - Vector2::new(prev_p.x, prev_p.y);
-                                let delta = Vector2::new(delta.x as i32, delta.y as i32);
+                                let delta = match self.grid_snap() {
+                                    // Move by whole cells, as the cursor crosses cell edges.
+                                    Some((gx, gy)) => {
+                                        let cell =
+                                            |n: f32, g: i32| (n.floor() as i32).div_euclid(g);
+                                        Vector2::new(
+                                            (cell(p.x, gx) - cell(prev_p.x, gx)) * gx,
+                                            (cell(p.y, gy) - cell(prev_p.y, gy)) * gy,
+                                        )
+                                    }
+                                    None => Vector2::new(delta.x as i32, delta.y as i32),
+                                };
                                 let t = Selection::from(s.bounds() + delta);
 
                                 if view.intersects(t.abs().bounds()) {
-                                    *s = t;
+                                    self.translate_selection(delta.x, delta.y);
                                 }
                             }
                         }
@@ -1993,6 +3188,85 @@ impl Session {
         self.cursor_dirty();
     }
 
+    /// Fill the selection, or if the gradient doesn't start inside the selection,
+    /// the area of same-colored pixels it starts on, with a gradient going from
+    /// the foreground to the background color.
+    fn gradient(&mut self, from: ViewCoords<i32>, to: ViewCoords<i32>) {
+        let v = self.active_view();
+        let (fw, fh) = (v.fw as i32, v.fh as i32);
+
+        if !v.bounds().contains(*from) {
+            return;
+        }
+        let selection = self
+            .selection
+            .map(|s| s.abs().bounds().intersection(v.bounds()))
+            .filter(|_| self.is_selected(from));
+
+        // When there's no selection, the gradient is confined to the frame it starts on.
+        let area = selection.unwrap_or_else(|| {
+            let x = from.x - from.x % fw;
+            Rect::new(x, 0, x + fw, fh)
+        });
+        let w = area.width() as usize;
+
+        let mut pixels = self.resources.lock().get_snapshot_rect(v.id, &area);
+        let mask = if selection.is_some() {
+            (0..pixels.len())
+                .map(|i| {
+                    let (x, y) = ((i % w) as i32, (i / w) as i32);
+                    self.is_selected(ViewCoords::new(area.x1 + x, area.y2 - y - 1))
+                })
+                .collect()
+        } else {
+            let (x, y) = ((from.x - area.x1) as usize, (area.y2 - from.y - 1) as usize);
+            let color = pixels[y * w + x];
+
+            util::flood_fill(&pixels, w, x, y, |c| c == color)
+        };
+        let gradient = Gradient {
+            shape: self.settings["gradient/shape"].gradient_shape(),
+            from: *from,
+            to: *to,
+            colors: self.gradient_colors(),
+            dither: self.settings["gradient/dither"].is_set(),
+        };
+
+        for (i, pixel) in pixels.iter_mut().enumerate().filter(|(i, _)| mask[*i]) {
+            let (x, y) = ((i % w) as i32, (i / w) as i32);
+            *pixel = gradient.color(Point2::new(area.x1 + x, area.y2 - y - 1));
+        }
+        self.active_view_mut().set_pixels(area, pixels);
+    }
+
+    /// The colors of a gradient going from the foreground to the background color.
+    /// If both colors are in the palette, the palette colors in between are used as
+    /// intermediate steps.
+    fn gradient_colors(&self) -> Vec<Rgba8> {
+        let colors = &self.palette.colors;
+        let fg = colors.iter().position(|c| *c == self.fg);
+        let bg = colors.iter().position(|c| *c == self.bg);
+
+        match (fg, bg) {
+            (Some(a), Some(b)) if a <= b => colors[a..=b].to_vec(),
+            (Some(a), Some(b)) => colors[b..=a].iter().rev().cloned().collect(),
+            _ => vec![self.fg, self.bg],
+        }
+    }
+
+    /// Pull the stabilized cursor towards the real cursor, as if attached to
+    /// it by a string of the given length. The stabilized cursor only moves
+    /// once the string is taut.
+    fn stabilize(prev: SessionCoords, cursor: SessionCoords, length: f32) -> SessionCoords {
+        let delta = Vector2::new(cursor.x - prev.x, cursor.y - prev.y);
+        let distance = delta.magnitude();
+
+        if distance <= length {
+            return prev;
+        }
+        prev + delta * ((distance - length) / distance)
+    }
+
     fn handle_received_character(&mut self, c: char) {
         if self.mode == Mode::Command {
             if c.is_control() {
@@ -2037,6 +3311,11 @@ impl Session {
                         self.switch_mode(Mode::Normal);
                         return;
                     }
+                    if key == platform::Key::Return && state == InputState::Pressed && self.floating
+                    {
+                        self.drop_selection();
+                        return;
+                    }
                 }
                 Mode::Visual(VisualState::Pasting) => {
                     if key == platform::Key::Escape && state == InputState::Pressed {
@@ -2050,11 +3329,41 @@ impl Session {
                             platform::Key::Backspace => {
                                 self.cmdline_handle_backspace();
                             }
-                            platform::Key::Return => {
-                                self.cmdline_handle_enter();
+                            platform::Key::Return => {
+                                self.cmdline_handle_enter();
+                            }
+                            platform::Key::Escape => {
+                                self.cmdline_hide();
+                            }
+                            platform::Key::Tab => {
+                                self.cmdline_handle_tab(modifiers.shift);
+                            }
+                            platform::Key::Up => {
+                                self.cmdline.history_prev();
+                            }
+                            platform::Key::Down => {
+                                self.cmdline.history_next();
+                            }
+                            platform::Key::Left => {
+                                self.cmdline.cursor_left();
+                            }
+                            platform::Key::Right => {
+                                self.cmdline.cursor_right();
+                            }
+                            platform::Key::Home => {
+                                self.cmdline.cursor_home();
                             }
-                            platform::Key::Escape => {
-                                self.cmdline_hide();
+                            platform::Key::End => {
+                                self.cmdline.cursor_end();
+                            }
+                            platform::Key::Delete => {
+                                self.cmdline.delete();
+                            }
+                            platform::Key::W if modifiers.ctrl => {
+                                self.cmdline.delete_word();
+                            }
+                            platform::Key::U if modifiers.ctrl => {
+                                self.cmdline.delete_to_start();
                             }
                             _ => {}
                         }
@@ -2070,19 +3379,71 @@ impl Session {
                 _ => {}
             }
 
-            if let Some(kb) = self
-                .key_bindings
-                .find(Key::Virtual(key), modifiers, state, self.mode)
+            if key == platform::Key::Escape && state == InputState::Pressed {
+                self.count = None;
+                self.key_sequence.clear();
+            }
+
+            // Keys pressed in sequence, eg. `g g`. While the keys pressed so far
+            // could be the start of a bound sequence, we wait for the next key.
+            if state == InputState::Pressed && !repeat && !Key::Virtual(key).is_modifier() {
+                let combo = (modifiers, Key::Virtual(key));
+                let mut sequence = std::mem::take(&mut self.key_sequence);
+                sequence.push(combo);
+
+                if self.key_bindings.is_prefix(&sequence, self.mode) {
+                    self.key_sequence = sequence;
+                    self.key_sequence_elapsed = time::Duration::from_secs(0);
+                    return;
+                }
+                sequence.pop();
+
+                if !sequence.is_empty() {
+                    if let Some(kb) = self
+                        .key_bindings
+                        .find(combo.1, modifiers, state, self.mode, &sequence)
+                    {
+                        self.key_binding(kb, state);
+                        return;
+                    }
+                    // The sequence isn't bound, so the keys pressed before
+                    // this one are handled on their own.
+                    self.key_sequence = sequence;
+                    self.flush_key_sequence();
+
+                    if self.key_bindings.is_prefix(&[combo], self.mode) {
+                        self.key_sequence = vec![combo];
+                        self.key_sequence_elapsed = time::Duration::from_secs(0);
+                        return;
+                    }
+                }
+            }
+
+            if let Some(kb) =
+                self.key_bindings
+                    .find(Key::Virtual(key), modifiers, state, self.mode, &[])
             {
                 // For toggle-like key bindings, we don't want to run the command
                 // on key repeats. For regular key bindings, we run the command
                 // depending on if it's supposed to repeat.
                 if (repeat && kb.command.repeats() && !kb.is_toggle) || !repeat {
-                    self.command(kb.command);
+                    self.key_binding(kb, state);
                 }
                 return;
             }
 
+            // Unbound number keys make up a count for the next key binding,
+            // eg. `5u` to undo five times. A count can't start with `0`.
+            if let (Mode::Normal | Mode::Visual(_), InputState::Pressed, Some(d)) =
+                (self.mode, state, key.digit())
+            {
+                if modifiers == ModifiersState::default() && (d > 0 || self.count.is_some()) {
+                    let n = self.count.unwrap_or(0).saturating_mul(10).saturating_add(d);
+                    self.count = Some(n.min(Command::MAX_COUNT));
+                    return;
+                }
+            }
+
             if let Execution::Recording { events, .. } = exec {
                 if key == platform::Key::End {
                     events.pop(); // Discard this key event.
@@ -2093,6 +3454,40 @@ impl Session {
         }
     }
 
+    /// Run the command of a key binding that was triggered.
+    fn key_binding(&mut self, kb: KeyBinding, state: InputState) {
+        // Toggle-like key bindings, such as modifier keys, keep the
+        // count for the next key binding. Other bindings drop it, unless
+        // their command can be repeated.
+        match self.count {
+            Some(n) if state == InputState::Pressed && !kb.is_toggle => {
+                self.count = None;
+
+                if kb.command.repeats() || kb.command.is_edit() {
+                    self.run(Command::Count(n, Box::new(kb.command)));
+                } else {
+                    self.run(kb.command);
+                }
+            }
+            _ => self.run(kb.command),
+        }
+    }
+
+    /// Run the key binding for the keys of the sequence pressed so far, if
+    /// there is one, and start a new sequence.
+    fn flush_key_sequence(&mut self) {
+        let mut sequence = std::mem::take(&mut self.key_sequence);
+
+        if let Some((modifiers, key)) = sequence.pop() {
+            if let Some(kb) =
+                self.key_bindings
+                    .find(key, modifiers, InputState::Pressed, self.mode, &sequence)
+            {
+                self.key_binding(kb, InputState::Pressed);
+            }
+        }
+    }
+
     ///////////////////////////////////////////////////////////////////////////
     /// Sourcing
     ///////////////////////////////////////////////////////////////////////////
@@ -2122,13 +3517,27 @@ impl Session {
 
     /// Source a script from an [`io::BufRead`].
     fn source_reader<P: AsRef<Path>, R: io::BufRead>(&mut self, r: R, _path: P) -> io::Result<()> {
+        // Conditional blocks may not span scripts.
+        let depth = self.conditions.len();
+        let result = self.source_lines(r);
+
+        if result.is_ok() && self.conditions.len() > depth {
+            self.conditions.truncate(depth);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "missing `end`"));
+        }
+        self.conditions.truncate(depth);
+
+        result
+    }
+
+    fn source_lines<R: io::BufRead>(&mut self, r: R) -> io::Result<()> {
         for (i, line) in r.lines().enumerate() {
             let line = line?;
 
             if line.starts_with(cmd::COMMENT) {
                 continue;
             }
-            match Command::from_str(&format!(":{}", line)) {
+            match Command::parse_in(&format!(":{}", line), &self.scope()) {
                 Err(e) => {
                     return Err(io::Error::new(
                         io::ErrorKind::Other,
@@ -2186,14 +3595,83 @@ impl Session {
     /// Center the selection to the given session coordinates.
     fn center_selection(&mut self, p: SessionCoords) {
         let c = self.active_view_coords(p);
+        let snap = self.grid_snap();
+
         if let Some(ref mut s) = self.selection {
             let r = s.abs().bounds();
             let (w, h) = (r.width(), r.height());
-            let (x, y) = (c.x as i32 - w / 2, c.y as i32 - h / 2);
+            let (mut x, mut y) = (c.x as i32 - w / 2, c.y as i32 - h / 2);
+
+            // Align the selection with the nearest grid lines.
+            if let Some((gx, gy)) = snap {
+                x = (x + gx / 2).div_euclid(gx) * gx;
+                y = (y + gy / 2).div_euclid(gy) * gy;
+            }
             *s = Selection::new(x, y, x + w, y + h);
         }
     }
 
+    /// Grid spacing, if selections should snap to the grid.
+    fn grid_snap(&self) -> Option<(i32, i32)> {
+        if !self.settings["grid/snap"].is_set() {
+            return None;
+        }
+        let (gx, gy): (u32, u32) = self.settings["grid/spacing"].clone().into();
+
+        if gx == 0 || gy == 0 {
+            return None;
+        }
+        Some((gx as i32, gy as i32))
+    }
+
+    /// Grid cell containing the given point.
+    fn grid_cell_containing(p: Point2<i32>, (gx, gy): (i32, i32)) -> Rect<i32> {
+        let (x, y) = (p.x.div_euclid(gx) * gx, p.y.div_euclid(gy) * gy);
+        Rect::new(x, y, x + gx, y + gy)
+    }
+
+    /// Area of the grid cell at the given column and row, for the given grid
+    /// spacing. Returns `None` if the cell is empty or out of range.
+    fn grid_cell(x: u32, y: u32, gx: u32, gy: u32) -> Option<Rect<i32>> {
+        let coord = |n: u32, g: u32| {
+            n.checked_mul(g)
+                .filter(|&n| n <= i32::MAX as u32)
+                .map(|n| n as i32)
+        };
+        let cell = Rect::new(
+            coord(x, gx)?,
+            coord(y, gy)?,
+            coord(x.checked_add(1)?, gx)?,
+            coord(y.checked_add(1)?, gy)?,
+        );
+        Some(cell).filter(|r| !r.is_empty())
+    }
+
+    /// Snap a selection being made with the cursor at `p` to the grid, if grid
+    /// snapping is enabled. The selection then covers the cell it was started
+    /// in, the cell under the cursor, and any cell in between. Its first corner
+    /// is kept in the cell it was started in, so that it can be extended further.
+    fn snap_selection(&self, s: Selection, p: Point2<i32>) -> Selection {
+        let grid = match self.grid_snap() {
+            Some(grid) => grid,
+            None => return s,
+        };
+        let a = Self::grid_cell_containing(Point2::new(s.x1, s.y1), grid);
+        let b = Self::grid_cell_containing(p, grid);
+
+        let (x1, x2) = if b.x1 >= a.x1 {
+            (a.x1, b.x2 - 1)
+        } else {
+            (a.x2 - 1, b.x1)
+        };
+        let (y1, y2) = if b.y1 >= a.y1 {
+            (a.y1, b.y2 - 1)
+        } else {
+            (a.y2 - 1, b.y1)
+        };
+        Selection(Rect::new(x1, y1, x2, y2))
+    }
+
     ///////////////////////////////////////////////////////////////////////////
     /// Zoom functions
     ///////////////////////////////////////////////////////////////////////////
@@ -2272,7 +3750,63 @@ impl Session {
     fn command(&mut self, cmd: Command) {
         debug!("command: {:?}", cmd);
 
+        // Inside a conditional branch that isn't taken, only look for the end
+        // of the branch.
+        if !self.conditions.iter().all(|(running, _)| *running) {
+            match cmd {
+                Command::If(_) => self.conditions.push((false, true)),
+                Command::Else => self.else_branch(),
+                Command::End => {
+                    self.conditions.pop();
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        if cmd.is_edit() {
+            self.edits += 1;
+        }
+
         match cmd {
+            Command::If(ref e) => match self.eval(e) {
+                Ok(v) => {
+                    let truthy = v.is_truthy();
+                    self.conditions.push((truthy, truthy));
+                }
+                Err(e) => {
+                    // Skip the whole block.
+                    self.conditions.push((false, true));
+                    self.message(format!("Error: {}", e), MessageType::Error);
+                }
+            },
+            Command::Else => {
+                if self.conditions.is_empty() {
+                    self.message("Error: `else` without `if`", MessageType::Error);
+                } else {
+                    self.else_branch();
+                }
+            }
+            Command::End => {
+                if self.conditions.pop().is_none() {
+                    self.message("Error: `end` without `if`", MessageType::Error);
+                }
+            }
+            Command::Let(name, e) => {
+                if self.settings.get(&name).is_some() {
+                    self.message(
+                        format!("Error: `{}` is a setting, use `:set` instead", name),
+                        MessageType::Error,
+                    );
+                    return;
+                }
+                match self.eval(&e) {
+                    Ok(v) => {
+                        self.variables.insert(name, v);
+                    }
+                    Err(e) => self.message(format!("Error: {}", e), MessageType::Error),
+                }
+            }
             Command::Mode(m) => {
                 self.toggle_mode(m);
             }
@@ -2356,8 +3890,15 @@ impl Session {
             }
             Command::ForceQuit => self.quit_view(self.views.active_id),
             Command::ForceQuitAll => self.quit(ExitReason::Normal),
-            Command::Echo(ref v) => {
-                let result = match v {
+            Command::Echo(ref e) => {
+                let v = match self.eval(e) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        self.message(format!("Error: {}", e), MessageType::Error);
+                        return;
+                    }
+                };
+                let result = match &v {
                     Value::Str(s) => Ok(Value::Str(s.clone())),
                     Value::Ident(s) => match s.as_str() {
                         "config/dir" => Ok(Value::Str(format!(
@@ -2376,6 +3917,7 @@ impl Session {
                             Some(result) => Ok(Value::Str(format!("{} = {}", v.clone(), result))),
                         },
                     },
+                    _ if !matches!(e, Expr::Value(_)) => Ok(v.clone()),
                     _ => Err(format!("Error: argument cannot be echoed")),
                 };
                 match result {
@@ -2428,6 +3970,18 @@ impl Session {
                     self.message("Settings reset to default values", MessageType::Okay);
                 }
             }
+            Command::Gradient((x1, y1), (x2, y2)) => {
+                let from = ViewCoords::new(x1, y1);
+
+                if self.active_view().bounds().contains(*from) {
+                    self.gradient(from, ViewCoords::new(x2, y2));
+                } else {
+                    self.message(
+                        format!("Error: {},{} is outside of the view", x1, y1),
+                        MessageType::Error,
+                    );
+                }
+            }
             Command::Fill(color) => {
                 self.active_view_mut().clear(color);
             }
@@ -2509,7 +4063,14 @@ impl Session {
                     v.touch();
                 }
             }
-            Command::Set(ref k, ref v) => {
+            Command::Set(ref k, ref e) => {
+                let v = &match self.eval(e) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        self.message(format!("Error: {}", e), MessageType::Error);
+                        return;
+                    }
+                };
                 if Settings::DEPRECATED.contains(&k.as_str()) {
                     self.message(
                         format!("Warning: the setting `{}` has been deprecated", k),
@@ -2522,6 +4083,8 @@ impl Session {
                         self.message(format!("Error: {}", e), MessageType::Error);
                     }
                     Ok(ref old) => {
+                        let v = &self.settings[k].clone();
+
                         if old != v {
                             self.setting_changed(k, old, v);
                         }
@@ -2530,7 +4093,9 @@ impl Session {
             }
             #[allow(mutable_borrow_reservation_conflict)]
             Command::Toggle(ref k) => match self.settings.get(k) {
-                Some(Value::Bool(b)) => self.command(Command::Set(k.clone(), Value::Bool(!b))),
+                Some(Value::Bool(b)) => {
+                    self.command(Command::Set(k.clone(), Expr::Value(Value::Bool(!b))))
+                }
                 Some(_) => {
                     self.message(format!("Error: can't toggle `{}`", k), MessageType::Error);
                 }
@@ -2575,8 +4140,11 @@ impl Session {
                 }
             }
             Command::Map(map) => {
+                let display = map.display();
                 let KeyMapping {
                     key,
+                    modifiers,
+                    sequence,
                     press,
                     release,
                     modes,
@@ -2584,20 +4152,22 @@ impl Session {
 
                 self.key_bindings.add(KeyBinding {
                     key,
+                    sequence,
                     modes: modes.clone(),
                     command: press,
                     state: InputState::Pressed,
-                    modifiers: platform::ModifiersState::default(),
+                    modifiers,
                     is_toggle: release.is_some(),
-                    display: Some(format!("{}", key)),
+                    display: Some(display),
                 });
                 if let Some(cmd) = release {
                     self.key_bindings.add(KeyBinding {
                         key,
+                        sequence: Vec::new(),
                         modes,
                         command: cmd,
                         state: InputState::Released,
-                        modifiers: platform::ModifiersState::default(),
+                        modifiers,
                         is_toggle: true,
                         display: None,
                     });
@@ -2622,9 +4192,79 @@ impl Session {
                 self.unimplemented();
             }
             Command::SelectionMove(x, y) => {
-                if let Some(ref mut s) = self.selection {
-                    s.translate(x, y);
+                self.lift_selection();
+                self.translate_selection(x, y);
+
+                // Moving floating pixels is an edit, moving the selection isn't.
+                if self.floating {
+                    self.edits += 1;
+                }
+            }
+            Command::SelectionShift(x, y) => {
+                self.transform_selection(|b| b.shift(x, y), false);
+            }
+            Command::SelectionFlip(axis) => match axis {
+                Axis::X => self.transform_selection(Bitmap::flip_x, false),
+                Axis::Y => self.transform_selection(Bitmap::flip_y, false),
+            },
+            Command::SelectionRotate(degrees) => {
+                let degrees = degrees.rem_euclid(360.);
+
+                if degrees % 90. == 0. {
+                    let quarter_turns = degrees as u32 / 90;
+                    self.transform_selection(|b| b.rotate(quarter_turns), false);
+                } else {
+                    self.rotate_selection(degrees);
+                }
+            }
+            Command::SelectionScale(x, y) => {
+                if x <= 0. || y <= 0. {
+                    self.message("Error: scale factors must be positive", MessageType::Error);
+                    return;
+                }
+                if let Some(s) = self.selection {
+                    let s = s.abs().bounds();
+
+                    if !self.check_scaled_size(
+                        s.width() as u32,
+                        s.height() as u32,
+                        x,
+                        y,
+                        "selection",
+                    ) {
+                        return;
+                    }
+                }
+                let epx = self.settings["scale/epx"].is_set();
+                self.transform_selection(|b| b.scale(x, y, epx), false);
+            }
+            Command::ViewScale(x, y) => {
+                if x <= 0. || y <= 0. {
+                    self.message("Error: scale factors must be positive", MessageType::Error);
+                    return;
+                }
+                self.scale_view(x, y);
+            }
+            Command::SelectionCell(x, y) => {
+                let (gx, gy): (u32, u32) = self.settings["grid/spacing"].clone().into();
+                let cell = Self::grid_cell(x, y, gx, gy)
+                    .filter(|r| r.intersects(self.active_view().bounds()));
+
+                let cell = if let Some(cell) = cell {
+                    cell
+                } else {
+                    self.message(
+                        format!("Error: grid cell {},{} is outside of the view", x, y),
+                        MessageType::Error,
+                    );
+                    return;
+                };
+                if !matches!(self.mode, Mode::Visual(VisualState::Selecting { .. })) {
+                    self.switch_mode(Mode::Visual(VisualState::default()));
                 }
+                self.drop_selection();
+                self.selection = Some(Selection::from(cell));
+                self.selection_mask = None;
             }
             Command::SelectionResize(x, y) => {
                 if let Some(ref mut s) = self.selection {
@@ -2678,34 +4318,157 @@ impl Session {
                 let v = self.active_view();
                 let r = v.bounds();
                 let fw = v.extent().fw as i32;
-                if let Some(s) = &mut self.selection {
-                    let mut t = *s;
+                if let Some(s) = self.selection {
+                    let mut t = s;
                     t.translate(fw * i32::from(dir), 0);
 
                     if r.intersects(t.abs().bounds()) {
-                        *s = t;
+                        self.translate_selection(fw * i32::from(dir), 0);
                     }
                 }
             }
-            Command::SelectionPaste => {
+            Command::SelectionPaste(None) => {
                 if let (Mode::Visual(VisualState::Pasting), Some(s)) = (self.mode, self.selection) {
-                    self.active_view_mut().paste(s.abs().bounds());
+                    self.paste_selection(s.abs().bounds());
                 } else {
                     // TODO: Enter paste mode?
                 }
             }
-            Command::SelectionYank => {
-                self.yank_selection();
+            Command::SelectionPaste(Some(r)) => {
+                self.paste_register(r);
+            }
+            Command::SelectionYank(r) => {
+                self.yank_selection(r);
             }
-            Command::SelectionCut => {
+            Command::SelectionCut(r) => {
                 // To mimick the behavior of `vi`, we yank the selection
                 // before deleting it.
-                if self.yank_selection().is_some() {
+                if self.yank_selection(r).is_some() {
                     self.command(Command::SelectionErase);
                 }
             }
+            Command::SelectionWrite(ref path) => {
+                if let Err(e) = self.save_selection_as(path) {
+                    self.message(format!("Error: {}", e), MessageType::Error);
+                }
+            }
+            Command::SelectionNew => {
+                self.selection_to_view();
+            }
+            Command::Repeat => {
+                if let Some(cmd) = self.last_edit.clone() {
+                    // Don't count this as an edit, so that commands which
+                    // repeat, such as `:repeat 3`, aren't remembered themselves.
+                    let edits = self.edits;
+                    self.command(cmd);
+                    self.edits = edits;
+                } else {
+                    self.message("Error: no edit to repeat", MessageType::Error);
+                }
+            }
+            Command::Count(n, cmd) => {
+                self.message_clear();
+
+                for _ in 0..n.min(Command::MAX_COUNT) {
+                    self.command((*cmd).clone());
+
+                    if self.message.is_error() {
+                        break;
+                    }
+                }
+            }
+            Command::Seq(cmds) => {
+                let n = cmds.len();
+
+                for (i, cmd) in cmds.into_iter().enumerate() {
+                    self.message_clear();
+                    self.command(cmd);
+
+                    // Abort the sequence on the first error.
+                    if self.message.is_error() {
+                        let msg =
+                            format!("{} (in command {} of {})", self.message.string, i + 1, n);
+                        self.message(msg, MessageType::Error);
+                        break;
+                    }
+                }
+            }
+            Command::Def(name, body) => {
+                if Command::is_builtin(&name) {
+                    self.message(
+                        format!("Error: ':{}' is a built-in command", name),
+                        MessageType::Error,
+                    );
+                } else {
+                    let body = if body.starts_with(':') {
+                        body
+                    } else {
+                        format!(":{}", body)
+                    };
+                    self.definitions.insert(name, body);
+                }
+            }
+            Command::DefList => {
+                let defs = self
+                    .definitions
+                    .iter()
+                    .map(|(name, body)| format!(":{} = {}", name, body))
+                    .collect::<Vec<_>>();
+
+                if defs.is_empty() {
+                    self.message("No user-defined commands", MessageType::Info);
+                } else {
+                    self.message(defs.join("  "), MessageType::Info);
+                }
+            }
+            Command::Call(name, args) => {
+                self.call(&name, &args);
+            }
+            Command::Deferred(body, args) => {
+                let scope = Scope {
+                    args,
+                    ..self.scope()
+                };
+                match Command::parse_in(&body, &scope) {
+                    Err(e) => {
+                        self.message(format!("Error: {} in `{}`", e, body), MessageType::Error)
+                    }
+                    Ok(cmd) => self.command(cmd),
+                }
+            }
+            Command::History(n) => {
+                let history = self.cmdline.history();
+                let history = history[history.len().saturating_sub(n)..].join("  ");
+
+                if history.is_empty() {
+                    self.message("No history", MessageType::Info);
+                } else {
+                    self.message(history, MessageType::Info);
+                }
+            }
+            Command::Registers => {
+                let registers = self
+                    .resources
+                    .lock()
+                    .registers
+                    .iter()
+                    .map(|(r, b)| format!("{}: {}x{}", r, b.w, b.h))
+                    .collect::<Vec<_>>();
+
+                if registers.is_empty() {
+                    self.message("No registers", MessageType::Info);
+                } else {
+                    self.message(registers.join("  "), MessageType::Info);
+                }
+            }
+            Command::Outline(color) => {
+                self.outline(color.unwrap_or(self.fg));
+            }
             Command::SelectionFill(color) => {
-                if let Some(s) = self.selection {
+                if let Some(mask) = self.selection_mask().cloned() {
+                    let color = color.unwrap_or(self.fg);
+                    self.paint_mask(&mask, |dst| Blend::Over.apply(color, dst));
+                } else if let Some(s) = self.selection {
                     self.effects
                         .push(Effect::ViewPaintFinal(vec![Shape::Rectangle(
                             s.abs().bounds().map(|n| n as f32),
@@ -2718,7 +4481,9 @@ impl Session {
                 }
             }
             Command::SelectionErase => {
-                if let Some(s) = self.selection {
+                if let Some(mask) = self.selection_mask().cloned() {
+                    self.paint_mask(&mask, |_| Rgba8::TRANSPARENT);
+                } else if let Some(s) = self.selection {
                     self.effects.extend_from_slice(&[
                         Effect::ViewBlendingChanged(Blending::constant()),
                         Effect::ViewPaintFinal(vec![Shape::Rectangle(
@@ -2747,6 +4512,123 @@ impl Session {
         }
     }
 
+    /// Switch the innermost conditional block to its `else` branch, which is only
+    /// run if no other branch was, and the enclosing blocks are being run.
+    fn else_branch(&mut self) {
+        if let Some(((running, taken), outer)) = self.conditions.split_last_mut() {
+            *running = !*taken && outer.iter().all(|(running, _)| *running);
+            *taken = true;
+        }
+    }
+
+    /// Evaluate an expression. Variables are looked up first, then settings,
+    /// then session and view properties.
+    fn eval(&self, e: &Expr) -> Result<Value, String> {
+        match e {
+            Expr::Value(v) => Ok(v.clone()),
+            Expr::Var(name) => self
+                .lookup(name)
+                .ok_or_else(|| format!("`${}` is undefined", name)),
+            Expr::Binary(lhs, op, rhs) => self.eval(lhs)?.apply(*op, &self.eval(rhs)?),
+        }
+    }
+
+    /// Look up a variable, setting or property, in that order.
+    fn lookup(&self, name: &str) -> Option<Value> {
+        self.variables
+            .get(name)
+            .or_else(|| self.settings.get(name))
+            .cloned()
+            .or_else(|| self.property(name))
+    }
+
+    /// Get a session or active view property, eg. `v/width`.
+    fn property(&self, name: &str) -> Option<Value> {
+        let v = self.views.active();
+
+        match name {
+            "s/hidpi" => Some(Value::F32(self.hidpi_factor)),
+            "v/width" => v.map(|v| Value::U32(v.width())),
+            "v/height" => v.map(|v| Value::U32(v.height())),
+            "v/fw" => v.map(|v| Value::U32(v.fw)),
+            "v/fh" => v.map(|v| Value::U32(v.fh)),
+            "v/frames" => v.map(|v| Value::U32(v.animation.len() as u32)),
+            "v/zoom" => v.map(|v| Value::F32(v.zoom as f64)),
+            _ => None,
+        }
+    }
+
+    /// Run a user-defined command, substituting its arguments for `$1`, `$2` etc.
+    fn call(&mut self, name: &str, args: &[String]) {
+        let body = match self.definitions.get(name) {
+            Some(body) => body.clone(),
+            None => {
+                self.message(
+                    format!("Error: unrecognized command ':{}'", name),
+                    MessageType::Error,
+                );
+                return;
+            }
+        };
+        if self.call_depth >= Self::MAX_CALL_DEPTH {
+            self.message(
+                format!("Error: maximum call depth exceeded in ':{}'", name),
+                MessageType::Error,
+            );
+            return;
+        }
+        // The body is parsed with the arguments in scope, so that each argument
+        // is substituted as a whole value, and can't change the body's meaning.
+        let scope = Scope {
+            args: args.to_vec(),
+            ..self.scope()
+        };
+        match Command::parse_in(&body, &scope) {
+            Err(e) => self.message(format!("Error: {} in ':{}'", e, name), MessageType::Error),
+            Ok(cmd) => {
+                self.call_depth += 1;
+                self.run_block(cmd);
+                self.call_depth -= 1;
+            }
+        }
+    }
+
+    /// The scope commands are parsed in. `$` references are looked up as they
+    /// are parsed: variables shadow settings, which shadow properties.
+    fn scope(&self) -> Scope<'_> {
+        Scope {
+            vars: Some(self),
+            args: Vec::new(),
+            commands: self.definitions.keys().cloned().collect(),
+        }
+    }
+
+    /// Run a command on its own, eg. from the command line or a key binding,
+    /// remembering it for `:repeat` if it edited the view. Commands made up of
+    /// other commands, such as sequences, are remembered if any of those did.
+    fn run(&mut self, cmd: Command) {
+        let edits = self.edits;
+        let last = cmd.clone();
+
+        self.run_block(cmd);
+
+        if self.edits != edits {
+            self.last_edit = Some(last);
+        }
+    }
+
+    /// Run a command, making sure conditional blocks opened by it are closed,
+    /// since they can't span commands run on their own.
+    fn run_block(&mut self, cmd: Command) {
+        let depth = self.conditions.len();
+        self.command(cmd);
+
+        if self.conditions.len() > depth {
+            self.conditions.truncate(depth);
+            self.message("Error: `if` without `end`", MessageType::Error);
+        }
+    }
+
     fn cmdline_handle_enter(&mut self) {
         let input = self.cmdline.input();
         // Always hide the command line before executing the command,
@@ -2757,10 +4639,41 @@ impl Session {
         if input.is_empty() {
             return;
         }
+        if let (true, Some(history)) = (self.cmdline.record(&input), &self.history) {
+            if let Err(e) = self.cmdline.append_history(history, &input) {
+                warn!("error saving history to {}: {}", history.display(), e);
+            }
+        }
 
-        match Command::from_str(&input) {
+        match Command::parse_in(&input, &self.scope()) {
             Err(e) => self.message(format!("Error: {}", e), MessageType::Error),
-            Ok(cmd) => self.command(cmd),
+            Ok(cmd) => self.run(cmd),
+        }
+    }
+
+    fn cmdline_handle_tab(&mut self, reverse: bool) {
+        let settings = &self.settings;
+        let definitions = &self.definitions;
+        let candidates = self
+            .cmdline
+            .complete(reverse, |completion, word| match completion {
+                Completion::Command => Completion::COMMANDS
+                    .iter()
+                    .copied()
+                    .chain(definitions.keys().map(String::as_str))
+                    .map(str::to_owned)
+                    .collect(),
+                Completion::Setting => settings.names().map(str::to_owned).collect(),
+                Completion::BrushMode => BrushMode::ALL.iter().map(|m| m.to_string()).collect(),
+                Completion::Tool => Completion::TOOLS.iter().map(|t| t.to_string()).collect(),
+                Completion::Path => cmd::complete_path(word),
+            });
+        let candidates = candidates.join(" ");
+
+        if candidates.is_empty() {
+            self.message_clear();
+        } else {
+            self.message(candidates, MessageType::Hint);
         }
     }
 
@@ -2816,3 +4729,142 @@ impl Session {
         }
     }
 }
+
+impl Variables for Session {
+    fn var(&self, name: &str) -> Option<String> {
+        self.lookup(name).map(|v| v.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(k: platform::Key) -> (ModifiersState, Key) {
+        (ModifiersState::default(), Key::Virtual(k))
+    }
+
+    fn bind(bindings: &mut KeyBindings, keys: &[platform::Key], command: Command) {
+        let mut sequence: Vec<_> = keys.iter().map(|k| key(*k)).collect();
+        let (modifiers, key) = sequence.pop().unwrap();
+
+        bindings.add(KeyBinding {
+            modes: vec![Mode::Normal],
+            modifiers,
+            key,
+            sequence,
+            state: InputState::Pressed,
+            command,
+            is_toggle: false,
+            display: None,
+        });
+    }
+
+    fn find(
+        bindings: &KeyBindings,
+        k: platform::Key,
+        mode: Mode,
+        sequence: &[(ModifiersState, Key)],
+    ) -> Option<Command> {
+        bindings
+            .find(
+                Key::Virtual(k),
+                ModifiersState::default(),
+                InputState::Pressed,
+                mode,
+                sequence,
+            )
+            .map(|kb| kb.command)
+    }
+
+    fn bindings() -> KeyBindings {
+        let mut bindings = KeyBindings { elems: Vec::new() };
+
+        bind(&mut bindings, &[platform::Key::G], Command::ViewNext);
+        bind(
+            &mut bindings,
+            &[platform::Key::G, platform::Key::G],
+            Command::ViewCenter,
+        );
+        bind(&mut bindings, &[platform::Key::U], Command::Undo);
+        bindings
+    }
+
+    #[test]
+    fn test_key_sequence() {
+        let bindings = bindings();
+        let g = key(platform::Key::G);
+
+        // `g` could be the start of `g g`, so it waits for the next key.
+        assert!(bindings.is_prefix(&[g], Mode::Normal));
+        assert!(!bindings.is_prefix(&[g, g], Mode::Normal));
+        assert!(matches!(
+            find(&bindings, platform::Key::G, Mode::Normal, &[g]),
+            Some(Command::ViewCenter)
+        ));
+        // On its own, eg. after a timeout, `g` runs its own binding.
+        assert!(matches!(
+            find(&bindings, platform::Key::G, Mode::Normal, &[]),
+            Some(Command::ViewNext)
+        ));
+    }
+
+    #[test]
+    fn test_key_sequence_interrupted() {
+        let bindings = bindings();
+        let (g, u) = (key(platform::Key::G), key(platform::Key::U));
+
+        // `g u` isn't bound: `g` and `u` are then run on their own.
+        assert!(!bindings.is_prefix(&[g, u], Mode::Normal));
+        assert!(find(&bindings, platform::Key::U, Mode::Normal, &[g]).is_none());
+        assert!(matches!(
+            find(&bindings, platform::Key::G, Mode::Normal, &[]),
+            Some(Command::ViewNext)
+        ));
+        assert!(!bindings.is_prefix(&[u], Mode::Normal));
+        assert!(matches!(
+            find(&bindings, platform::Key::U, Mode::Normal, &[]),
+            Some(Command::Undo)
+        ));
+    }
+
+    #[test]
+    fn test_key_sequence_mode_switch() {
+        let bindings = bindings();
+        let g = key(platform::Key::G);
+        let visual = Mode::Visual(VisualState::selecting());
+
+        // Keys pressed in normal mode don't complete or flush to bindings
+        // of other modes.
+        assert!(!bindings.is_prefix(&[g], visual));
+        assert!(find(&bindings, platform::Key::G, visual, &[g]).is_none());
+        assert!(find(&bindings, platform::Key::G, visual, &[]).is_none());
+    }
+
+    #[test]
+    fn test_settings_typed() {
+        let mut settings = Settings::default();
+
+        assert!(settings
+            .set("brush/blend", Value::Ident(String::from("multiply")))
+            .is_ok());
+        assert_eq!(settings["brush/blend"].blend(), Blend::Multiply);
+        assert!(settings
+            .set("gradient/shape", Value::Ident(String::from("radial")))
+            .is_ok());
+        assert_eq!(
+            settings["gradient/shape"].gradient_shape(),
+            GradientShape::Radial
+        );
+
+        // Invalid values are rejected, and the setting keeps its value.
+        assert!(settings
+            .set("selection/tool", Value::Ident(String::from("pen")))
+            .is_err());
+        assert!(settings.set("selection/tool", Value::U32(1)).is_err());
+        assert_eq!(
+            settings["selection/tool"].selection_tool(),
+            SelectionTool::Rect
+        );
+    }
+}
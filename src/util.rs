@@ -1,4 +1,4 @@
-use rgx::core::Rect;
+use rgx::core::{Rect, Rgba8};
 use rgx::math::Point2;
 
 pub fn clamp(p: &mut Point2<i32>, rect: Rect<i32>) {
@@ -16,6 +16,41 @@ pub fn clamp(p: &mut Point2<i32>, rect: Rect<i32>) {
     }
 }
 
+/// Flood-fill a pixel buffer of the given width, starting at `(x, y)`. Returns a mask
+/// of the pixels which match the predicate and are connected to the starting pixel
+/// horizontally or vertically.
+pub fn flood_fill<F>(pixels: &[Rgba8], w: usize, x: usize, y: usize, matches: F) -> Vec<bool>
+where
+    F: Fn(Rgba8) -> bool,
+{
+    let h = pixels.len() / w;
+    let mut mask = vec![false; pixels.len()];
+    let mut stack = vec![(x, y)];
+
+    while let Some((x, y)) = stack.pop() {
+        let i = y * w + x;
+
+        if mask[i] || !matches(pixels[i]) {
+            continue;
+        }
+        mask[i] = true;
+
+        if x > 0 {
+            stack.push((x - 1, y));
+        }
+        if x + 1 < w {
+            stack.push((x + 1, y));
+        }
+        if y > 0 {
+            stack.push((x, y - 1));
+        }
+        if y + 1 < h {
+            stack.push((x, y + 1));
+        }
+    }
+    mask
+}
+
 #[macro_export]
 macro_rules! hashmap {
     ($( $key: expr => $val: expr ),*) => {{
@@ -32,7 +32,7 @@ impl fmt::Display for ViewId {
 /// View coordinates.
 ///
 /// These coordinates are relative to the bottom left corner of the view.
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub struct ViewCoords<T>(Point2<T>);
 
 impl<T> ViewCoords<T> {
@@ -125,10 +125,10 @@ pub enum ViewOp {
     Blit(Rect<f32>, Rect<f32>),
     /// Clear to a color.
     Clear(Rgba8),
-    /// Yank the given area into the paste buffer.
-    Yank(Rect<i32>),
     /// Blit the paste buffer into the given area.
     Paste(Rect<i32>),
+    /// Replace the pixels of the given area. Rows are ordered from top to bottom.
+    SetPixels(Rect<i32>, Vec<Rgba8>),
 }
 
 /// A view on a sprite or image.
@@ -282,15 +282,26 @@ impl View {
         self.touch();
     }
 
-    pub fn yank(&mut self, area: Rect<i32>) {
-        self.ops.push(ViewOp::Yank(area));
-    }
-
     pub fn paste(&mut self, area: Rect<i32>) {
         self.ops.push(ViewOp::Paste(area));
         self.touch();
     }
 
+    /// Replace the pixels of an area of the view. Rows are ordered from top to bottom,
+    /// the same way they are returned by `ResourceManager::get_snapshot_rect`.
+    pub fn set_pixels(&mut self, area: Rect<i32>, pixels: Vec<Rgba8>) {
+        self.draw_pixels(area, pixels);
+        self.touch();
+    }
+
+    /// Like `set_pixels`, but without touching the view, so the change isn't
+    /// stored in a snapshot until the view is touched.
+    pub fn draw_pixels(&mut self, area: Rect<i32>, pixels: Vec<Rgba8>) {
+        debug_assert_eq!(pixels.len(), (area.width() * area.height()) as usize);
+
+        self.ops.push(ViewOp::SetPixels(area, pixels));
+    }
+
     /// Reset the view by providing frame size and number of frames.
     pub fn reset(&mut self, extent: ViewExtent) {
         self.fw = extent.fw;
@@ -361,6 +361,11 @@ impl renderer::Renderer for Renderer {
             .get(&v.id)
             .expect("the view data for the active view must exist");
         let view_ortho = kit::ortho(v.width(), v.height());
+        let tile_buf = if session.settings["tile-preview"].is_set() {
+            Some(draw::draw_view_tiles(session, v).finish(&self.r))
+        } else {
+            None
+        };
         let ortho = kit::ortho(
             self.screen_fb.width() as u32,
             self.screen_fb.height() as u32,
@@ -450,6 +455,13 @@ impl renderer::Renderer for Renderer {
                 p.draw(&checker_buf, &self.checker.binding);
             }
 
+            // Draw active view tile preview to screen framebuffer. This is
+            // drawn before the views, so that the views are drawn on top.
+            if let Some(buf) = &tile_buf {
+                p.set_pipeline(&self.sprite2d);
+                p.draw(buf, &view_data.anim_binding);
+            }
+
             // Draw view framebuffers to screen framebuffer.
             p.set_pipeline(&self.framebuffer2d);
             self.render_views(&mut p);
@@ -572,7 +584,28 @@ impl Renderer {
                 Effect::ViewPaintFinal(shapes) => {
                     shapes.into_iter().for_each(|s| self.final_batch.add(s));
                 }
+                Effect::PasteBufferChanged => {
+                    self.handle_paste_changed();
+                }
+            }
+        }
+    }
+
+    fn handle_paste_changed(&mut self) {
+        let resources = self.resources.lock();
+
+        if let Some(paste) = &resources.paste {
+            let (w, h) = (paste.w, paste.h);
+
+            if self.paste.texture.w != w || self.paste.texture.h != h {
+                self.paste.ready = false;
+                self.paste.texture = self.r.texture(w, h);
+                self.paste.binding =
+                    self.paste2d
+                        .binding(&self.r, &self.paste.texture, &self.sampler);
             }
+            self.r
+                .submit(&[Op::Fill(&self.paste.texture, &paste.pixels)]);
         }
     }
 
@@ -655,20 +688,6 @@ impl Renderer {
                 ViewOp::Blit(src, dst) => {
                     self.r.submit(&[Op::Blit(fb, *src, *dst)]);
                 }
-                ViewOp::Yank(src) => {
-                    let resources = self.resources.lock();
-                    let pixels = resources.get_snapshot_rect(v.id, src);
-                    let (w, h) = (src.width() as u32, src.height() as u32);
-
-                    if self.paste.texture.w != w || self.paste.texture.h != h {
-                        self.paste.ready = false;
-                        self.paste.texture = self.r.texture(w as u32, h as u32);
-                        self.paste.binding =
-                            self.paste2d
-                                .binding(&self.r, &self.paste.texture, &self.sampler);
-                    }
-                    self.r.submit(&[Op::Fill(&self.paste.texture, &pixels)]);
-                }
                 ViewOp::Paste(dst) => {
                     let buffer = sprite2d::Batch::singleton(
                         self.paste.texture.w,
@@ -684,6 +703,12 @@ impl Renderer {
 
                     self.paste.outputs.push(buffer);
                 }
+                ViewOp::SetPixels(area, pixels) => {
+                    let (w, h) = (area.width() as u32, area.height() as u32);
+                    let texels = Pixels::Rgba(pixels.clone().into()).into_bgra8();
+
+                    self.r.submit(&[Op::Transfer(fb, &texels, w, h, *area)]);
+                }
             }
         }
     }
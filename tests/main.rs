@@ -109,6 +109,101 @@ fn visual_mouse() {
     test("visual-mouse");
 }
 
+#[test]
+fn brush_wrap() {
+    test("brush-wrap");
+}
+
+#[test]
+fn brush_alpha_lock() {
+    test("brush-alpha-lock");
+}
+
+#[test]
+fn brush_blend() {
+    test("brush-blend");
+}
+
+#[test]
+fn brush_smoothing() {
+    test("brush-smoothing");
+}
+
+#[test]
+fn selection_transform() {
+    test("selection-transform");
+}
+
+#[test]
+fn selection_scale() {
+    test("selection-scale");
+}
+
+#[test]
+fn selection_rotsprite() {
+    test("selection-rotsprite");
+}
+
+#[test]
+fn registers() {
+    test("registers");
+}
+
+#[test]
+fn paste_options() {
+    test("paste-options");
+}
+
+#[test]
+fn selection_export() {
+    test("selection-export");
+}
+
+#[test]
+fn selection_float() {
+    test("selection-float");
+}
+
+#[test]
+fn outline() {
+    test("outline");
+}
+
+#[test]
+fn grid_snap() {
+    test("grid-snap");
+}
+
+#[test]
+fn command_seq() {
+    test("command-seq");
+}
+
+#[test]
+fn completion() {
+    test("completion");
+}
+
+#[test]
+fn history() {
+    test("history");
+}
+
+#[test]
+fn counts() {
+    test("counts");
+}
+
+#[test]
+fn repeat() {
+    test("repeat");
+}
+
+#[test]
+fn key_sequences() {
+    test("key-sequences");
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 fn test(name: &str) {